@@ -1,55 +1,217 @@
 use std::{
+    collections::{HashMap, HashSet},
     io::Read,
     path::Path,
-    process::{Command, Stdio},
-    sync::{Arc, Mutex},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     thread::{sleep, spawn, JoinHandle},
     time::Instant,
 };
 
 use crate::{
-    config::ActionConfig,
+    config::{ActionConfig, SecretEnvConfig},
     errors::GitOpsError,
-    receiver::{SourceType, WorkloadEvent},
+    receiver::{SourceType, Termination, WorkloadEvent},
+    sandbox::confine,
     utils::POLL_INTERVAL,
 };
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ActionResult {
     Success,
     Failure,
+    /// The action's when condition did not hold
+    Skipped,
 }
 
 #[derive(Clone)]
 pub struct Action {
     config: ActionConfig,
+    secrets: HashMap<String, String>,
 }
 
 impl Action {
     pub fn new(config: ActionConfig) -> Self {
-        Action { config }
+        Action {
+            config,
+            secrets: HashMap::new(),
+        }
     }
 
     pub fn id(&self) -> String {
         self.config.name.clone()
     }
 
+    pub fn config(&self) -> &ActionConfig {
+        &self.config
+    }
+
     pub fn set_env(&mut self, key: String, val: String) {
         self.config.environment.insert(key, val);
     }
+
+    /// Environment that is redacted from the action's output.
+    pub fn set_secrets(&mut self, secrets: HashMap<String, String>) {
+        self.secrets = secrets;
+    }
 }
 
-fn build_command(config: &ActionConfig, cwd: &Path) -> Command {
-    let mut command = Command::new(config.entrypoint.clone());
-    command.args(config.args.clone());
-    if !config.inherit_environment {
-        command.env_clear();
-        if let Ok(path) = std::env::var("PATH") {
-            command.env("PATH", path);
+/// Read secret_env sources; only allowed variables may be taken from our environment.
+pub fn resolve_secrets(
+    sources: &[SecretEnvConfig],
+    allowed: &HashSet<String>,
+) -> Result<HashMap<String, String>, GitOpsError> {
+    let mut secrets = HashMap::new();
+    for source in sources {
+        match source {
+            SecretEnvConfig::File { name, path } => {
+                secrets.insert(name.clone(), read_secret(path)?);
+            }
+            SecretEnvConfig::Env { name, from } => {
+                if !allowed.contains(from) {
+                    return Err(GitOpsError::SecretEnv(format!(
+                        "{} not allowed by --secret-env-allow",
+                        from
+                    )));
+                }
+                let value = std::env::var(from)
+                    .map_err(|err| GitOpsError::SecretEnv(format!("{}: {}", from, err)))?;
+                secrets.insert(name.clone(), value);
+            }
+            SecretEnvConfig::Dir(dir) => {
+                let entries = std::fs::read_dir(dir)
+                    .map_err(|err| GitOpsError::SecretEnv(format!("{}: {}", dir.display(), err)))?;
+                for entry in entries {
+                    let entry = entry.map_err(|err| {
+                        GitOpsError::SecretEnv(format!("{}: {}", dir.display(), err))
+                    })?;
+                    let file_name = entry.file_name().to_string_lossy().into_owned();
+                    // Kubernetes keeps the actual files in dot-prefixed directories
+                    if file_name.starts_with('.') || !entry.path().is_file() {
+                        continue;
+                    }
+                    let name = file_name
+                        .chars()
+                        .map(|c| {
+                            if c.is_ascii_alphanumeric() {
+                                c.to_ascii_uppercase()
+                            } else {
+                                '_'
+                            }
+                        })
+                        .collect();
+                    secrets.insert(name, read_secret(&entry.path())?);
+                }
+            }
+        }
+    }
+    Ok(secrets)
+}
+
+fn read_secret(path: &Path) -> Result<String, GitOpsError> {
+    let value = std::fs::read_to_string(path)
+        .map_err(|err| GitOpsError::SecretEnv(format!("{}: {}", path.display(), err)))?;
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    Ok(value.strip_suffix('\r').unwrap_or(value).to_owned())
+}
+
+/// Replaces secret values in action output.
+#[derive(Clone)]
+struct Redactor {
+    secrets: Vec<Vec<u8>>,
+}
+
+impl Redactor {
+    fn new<'a>(secrets: impl Iterator<Item = &'a String>) -> Self {
+        let secrets = secrets
+            .filter(|s| !s.is_empty())
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+        Redactor { secrets }
+    }
+
+    fn redact(&self, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        for secret in &self.secrets {
+            let mut redacted = Vec::with_capacity(data.len());
+            let mut pos = 0;
+            while pos < data.len() {
+                if data[pos..].starts_with(secret) {
+                    redacted.extend_from_slice(REDACTED);
+                    pos += secret.len();
+                } else {
+                    redacted.push(data[pos]);
+                    pos += 1;
+                }
+            }
+            data = redacted;
         }
+        data
     }
+
+    /// Output to hold back in case a secret continues in the next read.
+    fn overlap(&self) -> usize {
+        self.secrets.iter().map(|s| s.len() - 1).max().unwrap_or(0)
+    }
+}
+
+const REDACTED: &[u8] = b"[redacted]";
+
+/// Where containers see the workdir.
+const CONTAINER_WORKDIR: &str = "/workdir";
+
+/// Unique name for a container, so that it can be killed by name.
+fn container_name() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "kitops-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn build_command(
+    config: &ActionConfig,
+    secrets: &HashMap<String, String>,
+    cwd: &Path,
+    container: Option<&str>,
+) -> Command {
+    let mut command = match (&config.image, container) {
+        (Some(image), Some(container)) => {
+            // The runtime keeps our environment, e.g. DOCKER_HOST; the container only gets --env
+            let mut command = Command::new(&config.container_runtime);
+            command.args(["run", "--rm", "--name", container, "--volume"]);
+            command.arg(format!("{}:{}", cwd.display(), CONTAINER_WORKDIR));
+            command.args(["--workdir", CONTAINER_WORKDIR]);
+            // Values are passed through the runtime's environment to keep them off the command line
+            for key in config.environment.keys().chain(secrets.keys()) {
+                command.args(["--env", key.as_str()]);
+            }
+            command.args(["--entrypoint", config.entrypoint.as_str(), image.as_str()]);
+            command.args(config.args.clone());
+            command
+        }
+        _ => {
+            let mut command = Command::new(config.entrypoint.clone());
+            command.args(config.args.clone());
+            if !config.inherit_environment {
+                command.env_clear();
+                if let Ok(path) = std::env::var("PATH") {
+                    command.env("PATH", path);
+                }
+            }
+            command
+        }
+    };
     command.envs(config.environment.iter());
+    command.envs(secrets.iter());
     command.current_dir(cwd);
+    // Lead a new process group so that timeouts reach everything the action spawns
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
     command.stdout(Stdio::piped());
     command.stderr(Stdio::piped());
     command
@@ -59,6 +221,7 @@ fn emit_data<F, R>(
     name: String,
     mut source: R,
     source_type: SourceType,
+    redactor: Redactor,
     sink: &Arc<Mutex<F>>,
 ) -> JoinHandle<Result<(), GitOpsError>>
 where
@@ -68,41 +231,133 @@ where
     let sink = Arc::clone(sink);
     spawn(move || {
         let mut buf: [u8; 4096] = [0; 4096];
+        let mut pending = Vec::new();
         loop {
             let len = source.read(&mut buf).map_err(GitOpsError::ActionError)?;
+            pending.extend_from_slice(&buf[..len]);
+            let mut data = redactor.redact(&pending);
+            pending = if len == 0 {
+                Vec::new()
+            } else {
+                data.split_off(data.len() - redactor.overlap().min(data.len()))
+            };
+            if !data.is_empty() {
+                sink.lock().unwrap()(WorkloadEvent::ActionOutput(name.clone(), source_type, data))?;
+            }
             if len == 0 {
                 break;
             }
-            sink.lock().unwrap()(WorkloadEvent::ActionOutput(
-                name.clone(),
-                source_type,
-                buf[..len].into(),
-            ))?;
         }
         Ok::<(), GitOpsError>(())
     })
 }
 
+#[cfg(unix)]
+fn signal_group(child: &Child, signal: libc::c_int) -> Result<(), GitOpsError> {
+    #[allow(clippy::cast_possible_wrap)]
+    let pgid = child.id() as libc::pid_t;
+    if unsafe { libc::kill(-pgid, signal) } == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    // The whole group is already gone
+    if err.raw_os_error() == Some(libc::ESRCH) {
+        Ok(())
+    } else {
+        Err(GitOpsError::ActionError(err))
+    }
+}
+
+#[cfg(unix)]
+fn terminate(child: &mut Child) -> Result<(), GitOpsError> {
+    signal_group(child, libc::SIGTERM)
+}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) -> Result<(), GitOpsError> {
+    signal_group(child, libc::SIGKILL)
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) -> Result<(), GitOpsError> {
+    child.kill().map_err(GitOpsError::ActionError)
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) -> Result<(), GitOpsError> {
+    child.kill().map_err(GitOpsError::ActionError)
+}
+
+/// Failing to run the runtime is an error; a container that is already gone is not.
+fn kill_container(runtime: &str, container: &str) -> std::io::Result<()> {
+    Command::new(runtime)
+        .args(["kill", container])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|_| ())
+}
+
 pub fn run_action<F>(
     name: &str,
     action: &Action,
     cwd: &Path,
     deadline: Instant,
+    stop: &dyn Fn() -> bool,
     sink: &Arc<Mutex<F>>,
 ) -> Result<ActionResult, GitOpsError>
 where
     F: Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static,
 {
-    let mut command = build_command(&action.config, cwd);
+    let container = action.config.image.as_ref().map(|_| container_name());
+    let mut command = build_command(&action.config, &action.secrets, cwd, container.as_deref());
+    if let Some(ref sandbox) = action.config.sandbox {
+        confine(&mut command, sandbox, cwd)?;
+    }
     let mut child = command.spawn().map_err(GitOpsError::ActionError)?;
     let stdout = child.stdout.take().unwrap();
     let stderr = child.stderr.take().unwrap();
-    let out_t = emit_data(name.to_string(), stdout, SourceType::StdOut, sink);
-    let err_t = emit_data(name.to_string(), stderr, SourceType::StdErr, sink);
-    loop {
+    let redactor = Redactor::new(action.secrets.values());
+    let out_t = emit_data(
+        name.to_string(),
+        stdout,
+        SourceType::StdOut,
+        redactor.clone(),
+        sink,
+    );
+    let err_t = emit_data(name.to_string(), stderr, SourceType::StdErr, redactor, sink);
+    // Signalling the runtime client does not reliably stop the container
+    let stop_container = || {
+        let Some(ref container) = container else {
+            return Ok(());
+        };
+        match kill_container(&action.config.container_runtime, container) {
+            Ok(()) => Ok(()),
+            Err(err) => sink.lock().unwrap()(WorkloadEvent::ContainerKillFailed(
+                name.to_string(),
+                container.clone(),
+                format!("{}", err),
+            )),
+        }
+    };
+    let mut terminated = false;
+    let mut kill_at = None;
+    let res = loop {
         if let Some(exit) = child.try_wait().map_err(GitOpsError::ActionError)? {
+            if kill_at.is_some() || terminated {
+                // The group already had SIGTERM; stragglers would keep the output pipes open
+                kill(&mut child)?;
+                stop_container()?;
+            }
             out_t.join().unwrap()?;
             err_t.join().unwrap()?;
+            if kill_at.is_some() {
+                sink.lock().unwrap()(WorkloadEvent::Timeout(
+                    name.to_string(),
+                    Termination::Graceful(exit),
+                ))?;
+                break Ok(ActionResult::Failure);
+            }
             sink.lock().unwrap()(WorkloadEvent::ActionExit(name.to_string(), exit))?;
             if exit.success() {
                 break Ok(ActionResult::Success);
@@ -110,15 +365,34 @@ where
                 break Ok(ActionResult::Failure);
             }
         }
-        if Instant::now() > deadline {
-            child.kill().map_err(GitOpsError::ActionError)?;
+        if kill_at.is_some_and(|kill_at| Instant::now() > kill_at) {
+            kill(&mut child)?;
+            stop_container()?;
+            child.wait().map_err(GitOpsError::ActionError)?;
             out_t.join().unwrap()?;
             err_t.join().unwrap()?;
-            sink.lock().unwrap()(WorkloadEvent::Timeout(name.to_string()))?;
+            sink.lock().unwrap()(WorkloadEvent::Timeout(
+                name.to_string(),
+                Termination::Killed,
+            ))?;
             break Ok(ActionResult::Failure);
         }
+        if kill_at.is_none() && Instant::now() > deadline {
+            terminate(&mut child)?;
+            kill_at = Some(Instant::now() + action.config.termination_grace_period);
+        }
+        if !terminated && stop() {
+            // Let the action wind down; the deadline still applies
+            terminate(&mut child)?;
+            terminated = true;
+        }
         sleep(POLL_INTERVAL);
+    };
+    // Not the action's fault, so should not count against it
+    if terminated {
+        return Err(GitOpsError::ActionInterrupted(name.to_string()));
     }
+    res
 }
 
 #[cfg(test)]
@@ -141,7 +415,18 @@ mod tests {
                 args: vec!["-c".to_owned(), cmd.to_owned()],
                 environment: HashMap::new(),
                 inherit_environment: false,
+                timeout: None,
+                termination_grace_period: Duration::from_secs(1),
+                continue_on_error: false,
+                retries: 0,
+                when: None,
+                needs: Vec::new(),
+                image: None,
+                container_runtime: ActionConfig::default_container_runtime(),
+                sandbox: None,
+                secret_env: Vec::new(),
             },
+            secrets: HashMap::new(),
         }
     }
 
@@ -159,7 +444,7 @@ mod tests {
             events2.lock().unwrap().push(event);
             Ok(())
         }));
-        let res = run_action("test", &action, workdir.path(), deadline, &sink);
+        let res = run_action("test", &action, workdir.path(), deadline, &|| false, &sink);
         assert!(matches!(res, Ok(ActionResult::Success)));
         assert_eq!(
             vec![
@@ -181,7 +466,7 @@ mod tests {
         let workdir = tempdir().unwrap();
         let deadline = Instant::now() + Duration::from_secs(5);
         let sink = Arc::new(Mutex::new(move |_| Ok(())));
-        let res = run_action("test", &action, workdir.path(), deadline, &sink);
+        let res = run_action("test", &action, workdir.path(), deadline, &|| false, &sink);
         assert!(matches!(res, Ok(ActionResult::Failure)));
     }
 
@@ -192,7 +477,256 @@ mod tests {
         let workdir = tempdir().unwrap();
         let deadline = Instant::now();
         let sink = Arc::new(Mutex::new(move |_| Ok(())));
-        let res = run_action("test", &action, workdir.path(), deadline, &sink);
+        let res = run_action("test", &action, workdir.path(), deadline, &|| false, &sink);
+        assert!(matches!(res, Ok(ActionResult::Failure)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn terminate_action_on_shutdown() {
+        let action = shell_action("sleep 5");
+        let workdir = tempdir().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let sink = Arc::new(Mutex::new(move |_| Ok(())));
+        let res = run_action("test", &action, workdir.path(), deadline, &|| true, &sink);
+        assert!(matches!(res, Err(GitOpsError::ActionInterrupted(_))));
+        assert!(Instant::now() < deadline);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kill_action_ignoring_sigterm() {
+        let action = shell_action("trap '' TERM; sleep 5");
+        let workdir = tempdir().unwrap();
+        let started = Instant::now();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = events.clone();
+        let sink = Arc::new(Mutex::new(move |event| {
+            events2.lock().unwrap().push(event);
+            Ok(())
+        }));
+        let res = run_action("test", &action, workdir.path(), started, &|| false, &sink);
+        assert!(matches!(res, Ok(ActionResult::Failure)));
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert_eq!(
+            events.lock().unwrap().last(),
+            Some(&WorkloadEvent::Timeout(
+                "test".to_owned(),
+                Termination::Killed
+            ))
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn terminate_whole_process_group() {
+        let action = shell_action("sleep 5 & wait");
+        let workdir = tempdir().unwrap();
+        let started = Instant::now();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = events.clone();
+        let sink = Arc::new(Mutex::new(move |event| {
+            events2.lock().unwrap().push(event);
+            Ok(())
+        }));
+        let res = run_action("test", &action, workdir.path(), started, &|| false, &sink);
+        assert!(matches!(res, Ok(ActionResult::Failure)));
+        // The orphaned sleep would otherwise hold stdout open
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert!(matches!(
+            events.lock().unwrap().last(),
+            Some(WorkloadEvent::Timeout(_, Termination::Graceful(_)))
+        ));
+    }
+
+    #[cfg(unix)]
+    fn fake_runtime(dir: &Path, script: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("fake-runtime");
+        std::fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn run_action_in_container() {
+        let runtime_dir = tempdir().unwrap();
+        let mut action = shell_action("echo test");
+        action.config.image = Some("ze-image".to_owned());
+        action.config.container_runtime =
+            fake_runtime(runtime_dir.path(), "echo \"$@\"; echo \"$ZE_KEY\"");
+        action.set_env("ZE_KEY".to_owned(), "ze-value".to_owned());
+        let workdir = tempdir().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let output2 = output.clone();
+        let sink = Arc::new(Mutex::new(move |event| {
+            if let WorkloadEvent::ActionOutput(_, _, data) = event {
+                output2.lock().unwrap().extend(data);
+            }
+            Ok(())
+        }));
+        let res = run_action("test", &action, workdir.path(), deadline, &|| false, &sink);
+        assert!(matches!(res, Ok(ActionResult::Success)));
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let (args, env) = output.split_once('\n').unwrap();
+        assert!(args.starts_with("run --rm --name kitops-"));
+        assert!(args.ends_with(&format!(
+            "--volume {}:/workdir --workdir /workdir --env ZE_KEY --entrypoint /bin/sh ze-image -c echo test",
+            workdir.path().display()
+        )));
+        assert_eq!(env, "ze-value\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kill_container_after_grace_period() {
+        let runtime_dir = tempdir().unwrap();
+        let mut action = shell_action("true");
+        action.config.image = Some("ze-image".to_owned());
+        action.config.container_runtime = fake_runtime(
+            runtime_dir.path(),
+            &format!(
+                "if [ \"$1\" = kill ]; then touch {}/killed; else trap '' TERM; sleep 5; fi",
+                runtime_dir.path().display()
+            ),
+        );
+        let workdir = tempdir().unwrap();
+        let sink = Arc::new(Mutex::new(move |_| Ok(())));
+        let res = run_action(
+            "test",
+            &action,
+            workdir.path(),
+            Instant::now(),
+            &|| false,
+            &sink,
+        );
         assert!(matches!(res, Ok(ActionResult::Failure)));
+        assert!(runtime_dir.path().join("killed").exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn report_container_kill_failure() {
+        let runtime_dir = tempdir().unwrap();
+        let mut action = shell_action("true");
+        action.config.image = Some("ze-image".to_owned());
+        // Gone by the time kitops tries to kill the container
+        action.config.container_runtime =
+            fake_runtime(runtime_dir.path(), "rm \"$0\"; trap '' TERM; sleep 5");
+        let workdir = tempdir().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = events.clone();
+        let sink = Arc::new(Mutex::new(move |event| {
+            events2.lock().unwrap().push(event);
+            Ok(())
+        }));
+        let res = run_action(
+            "test",
+            &action,
+            workdir.path(),
+            Instant::now(),
+            &|| false,
+            &sink,
+        );
+        assert!(matches!(res, Ok(ActionResult::Failure)));
+        assert!(events.lock().unwrap().iter().any(
+            |e| matches!(e, WorkloadEvent::ContainerKillFailed(name, _, _) if name == "test")
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kill_container_on_shutdown() {
+        let runtime_dir = tempdir().unwrap();
+        let mut action = shell_action("true");
+        action.config.image = Some("ze-image".to_owned());
+        action.config.container_runtime = fake_runtime(
+            runtime_dir.path(),
+            &format!(
+                "if [ \"$1\" = kill ]; then touch {}/killed; else sleep 5; fi",
+                runtime_dir.path().display()
+            ),
+        );
+        let workdir = tempdir().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let sink = Arc::new(Mutex::new(move |_| Ok(())));
+        let res = run_action("test", &action, workdir.path(), deadline, &|| true, &sink);
+        assert!(matches!(res, Err(GitOpsError::ActionInterrupted(_))));
+        assert!(runtime_dir.path().join("killed").exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn redact_secrets_from_output() {
+        let mut action =
+            shell_action("echo \"x $ZE_SECRET y\"; printf ze-se; sleep 0.1; echo cret");
+        action.set_secrets(HashMap::from([(
+            "ZE_SECRET".to_owned(),
+            "ze-secret".to_owned(),
+        )]));
+        let workdir = tempdir().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let output2 = output.clone();
+        let sink = Arc::new(Mutex::new(move |event| {
+            if let WorkloadEvent::ActionOutput(_, _, data) = event {
+                output2.lock().unwrap().extend(data);
+            }
+            Ok(())
+        }));
+        let res = run_action("test", &action, workdir.path(), deadline, &|| false, &sink);
+        assert!(matches!(res, Ok(ActionResult::Success)));
+        assert_eq!(
+            String::from_utf8(output.lock().unwrap().clone()).unwrap(),
+            "x [redacted] y\n[redacted]\n"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kill_stragglers_on_shutdown() {
+        // The background sleep ignores SIGTERM and holds on to the output pipes
+        let action = shell_action("(trap '' TERM; sleep 10) & sleep 10");
+        let workdir = tempdir().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let sink = Arc::new(Mutex::new(move |_| Ok(())));
+        let started = Instant::now();
+        let res = run_action("test", &action, workdir.path(), deadline, &|| true, &sink);
+        assert!(matches!(res, Err(GitOpsError::ActionInterrupted(_))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn resolve_secret_sources() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("token"), "ze-token\n").unwrap();
+        let mounted = dir.path().join("mounted");
+        std::fs::create_dir(&mounted).unwrap();
+        std::fs::write(mounted.join("db-password"), "ze-password").unwrap();
+        std::fs::create_dir(mounted.join("..data")).unwrap();
+        let sources = vec![
+            SecretEnvConfig::File {
+                name: "TOKEN".to_owned(),
+                path: dir.path().join("token"),
+            },
+            SecretEnvConfig::Dir(mounted),
+        ];
+        let secrets = resolve_secrets(&sources, &HashSet::new()).unwrap();
+        assert_eq!(
+            secrets,
+            HashMap::from([
+                ("TOKEN".to_owned(), "ze-token".to_owned()),
+                ("DB_PASSWORD".to_owned(), "ze-password".to_owned()),
+            ])
+        );
+        let sources = vec![SecretEnvConfig::Env {
+            name: "HOME".to_owned(),
+            from: "HOME".to_owned(),
+        }];
+        let res = resolve_secrets(&sources, &HashSet::new());
+        assert!(matches!(res, Err(GitOpsError::SecretEnv(_))));
     }
 }
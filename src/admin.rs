@@ -0,0 +1,297 @@
+use std::{collections::HashSet, fmt::Write, time::SystemTime};
+
+use gix::{hash::Kind, ObjectId};
+
+use crate::{
+    errors::GitOpsError,
+    opts::StateCommand,
+    state::{RunResult, State},
+    store::Store,
+};
+
+/// Perform a `kitops state` subcommand, returning what to print. Only
+/// prune and set-sha for a task without state need the configured task
+/// ids, so they are loaded on demand.
+pub fn run_state_command(
+    command: &StateCommand,
+    store: &mut dyn Store,
+    configured: impl FnOnce() -> Result<HashSet<String>, GitOpsError>,
+) -> Result<String, GitOpsError> {
+    match command {
+        StateCommand::List => Ok(list(store)),
+        StateCommand::Show { task } => {
+            let state = store
+                .get(task)
+                .ok_or_else(|| GitOpsError::UnknownTask(task.clone()))?;
+            Ok(show(task, state))
+        }
+        StateCommand::Reset { task } => {
+            let mut state = store
+                .get(task)
+                .cloned()
+                .ok_or_else(|| GitOpsError::UnknownTask(task.clone()))?;
+            state.current_sha = ObjectId::null(Kind::Sha1);
+            state.given_up_sha = None;
+            state.failed_attempts = 0;
+            state.failed_fetches = 0;
+            state.next_run = SystemTime::now();
+            store.persist(task.clone(), &state)?;
+            Ok(format!("Reset {}; actions will run on next check\n", task))
+        }
+        StateCommand::SetSha { task, sha } => {
+            let sha = ObjectId::from_hex(sha.as_bytes())
+                .map_err(|_| GitOpsError::InvalidSha(sha.clone()))?;
+            let mut state = match store.get(task) {
+                Some(state) => state.clone(),
+                // The task may never have completed a run, e.g. if it failed from the start
+                None if configured()?.contains(task) => State::default(),
+                None => return Err(GitOpsError::UnknownTask(task.clone())),
+            };
+            state.current_sha = sha;
+            state.given_up_sha = None;
+            state.failed_attempts = 0;
+            state.failed_fetches = 0;
+            state.consecutive_failures = 0;
+            store.persist(task.clone(), &state)?;
+            Ok(format!("Marked {} as deployed for {}\n", sha, task))
+        }
+        StateCommand::Prune => {
+            let configured = configured()?;
+            let mut output = String::new();
+            for id in sorted_ids(store) {
+                if !configured.contains(&id) {
+                    store.remove(&id)?;
+                    writeln!(output, "Removed {}", id).unwrap();
+                }
+            }
+            Ok(output)
+        }
+    }
+}
+
+fn sorted_ids(store: &dyn Store) -> Vec<String> {
+    let mut ids = store.ids();
+    ids.sort();
+    ids
+}
+
+fn list(store: &dyn Store) -> String {
+    let ids = sorted_ids(store);
+    let width = ids.iter().map(String::len).max().unwrap_or(0).max(4);
+    let mut output = format!(
+        "{:<width$}  {:<40}  {:<20}  LAST RESULT\n",
+        "TASK", "SHA", "NEXT RUN"
+    );
+    for id in ids {
+        let state = store.get(&id).unwrap();
+        writeln!(
+            output,
+            "{:<width$}  {:<40}  {:<20}  {}",
+            id,
+            format_sha(Some(state.current_sha)),
+            humantime::format_rfc3339_seconds(state.next_run).to_string(),
+            format_result(state.last_result.as_ref()),
+        )
+        .unwrap();
+    }
+    output
+}
+
+fn show(id: &str, state: &State) -> String {
+    let mut output = String::new();
+    let fields = [
+        ("task", id.to_owned()),
+        ("current_sha", format_sha(Some(state.current_sha))),
+        (
+            "next_run",
+            humantime::format_rfc3339_seconds(state.next_run).to_string(),
+        ),
+        ("failed_attempts", state.failed_attempts.to_string()),
+        ("failed_fetches", state.failed_fetches.to_string()),
+        ("given_up_sha", format_sha(state.given_up_sha)),
+        (
+            "consecutive_failures",
+            state.consecutive_failures.to_string(),
+        ),
+        ("last_attempted_sha", format_sha(state.last_attempted_sha)),
+        ("last_result", format_result(state.last_result.as_ref())),
+        ("last_run_start", format_time(state.last_run_start)),
+        ("last_run_end", format_time(state.last_run_end)),
+    ];
+    for (name, value) in fields {
+        writeln!(output, "{}: {}", name, value).unwrap();
+    }
+    output
+}
+
+fn format_sha(sha: Option<ObjectId>) -> String {
+    match sha {
+        Some(sha) if !sha.is_null() => sha.to_string(),
+        _ => "-".to_owned(),
+    }
+}
+
+fn format_time(time: Option<SystemTime>) -> String {
+    time.map_or_else(
+        || "-".to_owned(),
+        |t| humantime::format_rfc3339_seconds(t).to_string(),
+    )
+}
+
+fn format_result(result: Option<&RunResult>) -> String {
+    match result {
+        None => "-".to_owned(),
+        Some(RunResult::Success) => "success".to_owned(),
+        Some(RunResult::Failure { action, message }) => {
+            format!("failure in {}: {}", action, message)
+        }
+        Some(RunResult::Error { message }) => format!("error: {}", message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use gix::{hash::Kind, ObjectId};
+
+    use crate::{
+        errors::GitOpsError,
+        opts::StateCommand,
+        state::{RunResult, State},
+        store::{FileStore, Store},
+    };
+
+    use super::run_state_command;
+
+    fn store(dir: &tempfile::TempDir) -> FileStore {
+        let mut store = FileStore::from_file(&dir.path().join("state.yaml")).unwrap();
+        let state = State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            given_up_sha: Some(ObjectId::empty_tree(Kind::Sha1)),
+            failed_attempts: 3,
+            last_result: Some(RunResult::Success),
+            ..Default::default()
+        };
+        store.persist("ze-task".to_owned(), &state).unwrap();
+        store.persist("old-task".to_owned(), &state).unwrap();
+        store
+    }
+
+    fn no_config() -> Result<HashSet<String>, GitOpsError> {
+        panic!("config not needed")
+    }
+
+    #[test]
+    fn list_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let output = run_state_command(&StateCommand::List, &mut store, no_config).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("old-task"));
+        assert!(lines[2].contains(&ObjectId::empty_blob(Kind::Sha1).to_string()));
+        assert!(lines[2].ends_with("success"));
+    }
+
+    #[test]
+    fn show_unknown_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let cmd = StateCommand::Show {
+            task: "no-task".to_owned(),
+        };
+        let res = run_state_command(&cmd, &mut store, no_config);
+        assert!(matches!(res, Err(GitOpsError::UnknownTask(_))));
+    }
+
+    #[test]
+    fn reset_forgets_sha() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let cmd = StateCommand::Reset {
+            task: "ze-task".to_owned(),
+        };
+        run_state_command(&cmd, &mut store, no_config).unwrap();
+        let store = FileStore::from_file(&dir.path().join("state.yaml")).unwrap();
+        let state = store.get("ze-task").unwrap();
+        assert!(state.current_sha.is_null());
+        assert_eq!(state.given_up_sha, None);
+        assert_eq!(state.failed_attempts, 0);
+    }
+
+    #[test]
+    fn set_sha_marks_deployed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let sha = ObjectId::empty_tree(Kind::Sha1);
+        let cmd = StateCommand::SetSha {
+            task: "ze-task".to_owned(),
+            sha: sha.to_string(),
+        };
+        run_state_command(&cmd, &mut store, no_config).unwrap();
+        let store = FileStore::from_file(&dir.path().join("state.yaml")).unwrap();
+        let state = store.get("ze-task").unwrap();
+        assert_eq!(state.current_sha, sha);
+        assert_eq!(state.given_up_sha, None);
+    }
+
+    #[test]
+    fn set_sha_for_configured_task_without_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let sha = ObjectId::empty_tree(Kind::Sha1);
+        let cmd = StateCommand::SetSha {
+            task: "new-task".to_owned(),
+            sha: sha.to_string(),
+        };
+        run_state_command(&cmd, &mut store, || {
+            Ok(HashSet::from(["new-task".to_owned()]))
+        })
+        .unwrap();
+        let store = FileStore::from_file(&dir.path().join("state.yaml")).unwrap();
+        assert_eq!(store.get("new-task").unwrap().current_sha, sha);
+    }
+
+    #[test]
+    fn set_sha_refuses_unknown_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let cmd = StateCommand::SetSha {
+            task: "ze-typo".to_owned(),
+            sha: ObjectId::empty_tree(Kind::Sha1).to_string(),
+        };
+        let res = run_state_command(&cmd, &mut store, || {
+            Ok(HashSet::from(["ze-task".to_owned()]))
+        });
+        assert!(matches!(res, Err(GitOpsError::UnknownTask(_))));
+        let store = FileStore::from_file(&dir.path().join("state.yaml")).unwrap();
+        assert!(store.get("ze-typo").is_none());
+    }
+
+    #[test]
+    fn set_sha_refuses_malformed_sha() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let cmd = StateCommand::SetSha {
+            task: "ze-task".to_owned(),
+            sha: "main".to_owned(),
+        };
+        let res = run_state_command(&cmd, &mut store, no_config);
+        assert!(matches!(res, Err(GitOpsError::InvalidSha(_))));
+    }
+
+    #[test]
+    fn prune_removes_unconfigured_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = store(&dir);
+        let output = run_state_command(&StateCommand::Prune, &mut store, || {
+            Ok(HashSet::from(["ze-task".to_owned()]))
+        })
+        .unwrap();
+        assert_eq!(output, "Removed old-task\n");
+        let store = FileStore::from_file(&dir.path().join("state.yaml")).unwrap();
+        assert!(store.get("old-task").is_none());
+        assert!(store.get("ze-task").is_some());
+    }
+}
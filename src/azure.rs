@@ -0,0 +1,677 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::Mutex,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{
+    blocking::{Client, ClientBuilder, Response},
+    header::CONTENT_LENGTH,
+    Method, StatusCode,
+};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::{
+    errors::GitOpsError,
+    state::State,
+    store::{apply_update, deserialize_state, serialize_state, Store},
+};
+
+const API_VERSION: &str = "2021-08-06";
+const IMDS_TOKEN_URL: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+/// Shortest lease Azure allows; we only hold it while writing.
+const LEASE_SECONDS: &str = "15";
+/// Wait between attempts to take a lease that another instance holds. Give up
+/// well before a lease left by a crashed instance expires; the scheduler
+/// retries the write later.
+const LEASE_RETRY_DELAY: Duration = Duration::from_millis(250);
+const LEASE_ATTEMPTS: usize = 4;
+
+pub enum AzureAuth {
+    /// Account key, as base64 in the portal
+    SharedKey(String),
+    /// SAS token query string, with or without leading '?'
+    Sas(String),
+    /// Token from the instance metadata service, optionally for a user-assigned identity
+    ManagedIdentity(Option<String>),
+}
+
+impl AzureAuth {
+    /// AZURE_STORAGE_SAS_TOKEN or AZURE_STORAGE_KEY if set, otherwise managed
+    /// identity, with client id from AZURE_CLIENT_ID for a user-assigned identity.
+    pub fn from_env() -> Self {
+        if let Ok(sas) = env::var("AZURE_STORAGE_SAS_TOKEN") {
+            Self::Sas(sas)
+        } else if let Ok(key) = env::var("AZURE_STORAGE_KEY") {
+            Self::SharedKey(key)
+        } else {
+            Self::ManagedIdentity(env::var("AZURE_CLIENT_ID").ok())
+        }
+    }
+}
+
+/// A single block blob, accessed through the Blob service REST API.
+pub struct AzureBlob {
+    account: String,
+    url: String,
+    /// URL path, which shared key signatures cover
+    path: String,
+    auth: AzureAuth,
+    token: Mutex<Option<(String, Instant)>>,
+    client: Client,
+}
+
+impl AzureBlob {
+    /// Endpoint is e.g. https://<account>.blob.core.windows.net or, for
+    /// Azurite, http://127.0.0.1:10000/<account>.
+    pub fn new(
+        endpoint: &str,
+        account: &str,
+        container: &str,
+        blob: &str,
+        auth: AzureAuth,
+    ) -> Result<Self, GitOpsError> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let base_path = endpoint
+            .split_once("://")
+            .and_then(|(_, rest)| rest.split_once('/'))
+            .map_or(String::new(), |(_, path)| format!("/{}", path));
+        let client = ClientBuilder::new()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|err| GitOpsError::AzureConfig(format!("failed to create client: {}", err)))?;
+        Ok(Self {
+            account: account.to_owned(),
+            url: format!("{}/{}/{}", endpoint, container, blob),
+            path: format!("{}/{}/{}", base_path, container, blob),
+            auth,
+            token: Mutex::new(None),
+            client,
+        })
+    }
+
+    /// Parse azblob://<account>/<container>/<blob>, taking auth and optionally
+    /// endpoint (AZURE_STORAGE_BLOB_ENDPOINT) from the environment.
+    pub fn from_url(url: &str) -> Result<Self, GitOpsError> {
+        let mut parts = url
+            .strip_prefix("azblob://")
+            .ok_or_else(|| GitOpsError::InvalidStateUrl(url.to_owned()))?
+            .splitn(3, '/');
+        let (Some(account), Some(container), Some(blob)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(GitOpsError::InvalidStateUrl(url.to_owned()));
+        };
+        if account.is_empty() || container.is_empty() || blob.is_empty() {
+            return Err(GitOpsError::InvalidStateUrl(url.to_owned()));
+        }
+        let endpoint = env::var("AZURE_STORAGE_BLOB_ENDPOINT")
+            .unwrap_or_else(|_| format!("https://{}.blob.core.windows.net", account));
+        Self::new(&endpoint, account, container, blob, AzureAuth::from_env())
+    }
+
+    /// Returns None if the blob does not exist.
+    fn get(&self) -> Result<Option<Vec<u8>>, GitOpsError> {
+        let res = self.send(Method::GET, None, Vec::new(), Vec::new())?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => res
+                .bytes()
+                .map(|body| Some(body.to_vec()))
+                .map_err(GitOpsError::AzureNetworkError),
+            _ => Err(self.api_error(res)),
+        }
+    }
+
+    /// Leases require an existing blob; losing a race to create it is fine.
+    fn create_if_missing(&self) -> Result<(), GitOpsError> {
+        let headers = vec![
+            ("if-none-match", "*".to_owned()),
+            ("x-ms-blob-type", "BlockBlob".to_owned()),
+        ];
+        let body = serialize_state(&HashMap::new())?;
+        let res = self.send(Method::PUT, None, headers, body)?;
+        match res.status() {
+            StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED => Ok(()),
+            status if status.is_success() => Ok(()),
+            _ => Err(self.api_error(res)),
+        }
+    }
+
+    fn put(&self, body: Vec<u8>, lease_id: &str) -> Result<(), GitOpsError> {
+        let headers = vec![
+            ("x-ms-blob-type", "BlockBlob".to_owned()),
+            ("x-ms-lease-id", lease_id.to_owned()),
+        ];
+        let res = self.send(Method::PUT, None, headers, body)?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(self.api_error(res))
+        }
+    }
+
+    /// Returns None if another instance holds the lease.
+    fn acquire_lease(&self) -> Result<Option<String>, GitOpsError> {
+        let headers = vec![
+            ("x-ms-lease-action", "acquire".to_owned()),
+            ("x-ms-lease-duration", LEASE_SECONDS.to_owned()),
+        ];
+        let res = self.send(Method::PUT, Some("lease"), headers, Vec::new())?;
+        match res.status() {
+            StatusCode::CONFLICT => Ok(None),
+            status if status.is_success() => Ok(res
+                .headers()
+                .get("x-ms-lease-id")
+                .and_then(|id| id.to_str().ok())
+                .map(ToOwned::to_owned)),
+            _ => Err(self.api_error(res)),
+        }
+    }
+
+    fn release_lease(&self, lease_id: &str) -> Result<(), GitOpsError> {
+        let headers = vec![
+            ("x-ms-lease-action", "release".to_owned()),
+            ("x-ms-lease-id", lease_id.to_owned()),
+        ];
+        let res = self.send(Method::PUT, Some("lease"), headers, Vec::new())?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(self.api_error(res))
+        }
+    }
+
+    fn send(
+        &self,
+        method: Method,
+        comp: Option<&str>,
+        extra_headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    ) -> Result<Response, GitOpsError> {
+        let mut headers = vec![
+            (
+                "x-ms-date",
+                Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            ),
+            ("x-ms-version", API_VERSION.to_owned()),
+        ];
+        headers.extend(extra_headers);
+        let mut query = comp
+            .map(|comp| format!("comp={}", comp))
+            .into_iter()
+            .collect::<Vec<_>>();
+        match &self.auth {
+            AzureAuth::SharedKey(key) => {
+                let string_to_sign = string_to_sign(
+                    &self.account,
+                    &self.path,
+                    method.as_str(),
+                    comp,
+                    &headers,
+                    body.len(),
+                );
+                let signature = shared_key_signature(key, &string_to_sign)?;
+                headers.push((
+                    "authorization",
+                    format!("SharedKey {}:{}", self.account, signature),
+                ));
+            }
+            AzureAuth::Sas(sas) => query.push(sas.trim_start_matches('?').to_owned()),
+            AzureAuth::ManagedIdentity(client_id) => {
+                let token = self.managed_identity_token(client_id.as_deref())?;
+                headers.push(("authorization", format!("Bearer {}", token)));
+            }
+        }
+        let url = if query.is_empty() {
+            self.url.clone()
+        } else {
+            format!("{}?{}", self.url, query.join("&"))
+        };
+        let mut request = self.client.request(method, url);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        request
+            .header(CONTENT_LENGTH, body.len())
+            .body(body)
+            .send()
+            .map_err(GitOpsError::AzureNetworkError)
+    }
+
+    /// Tokens are cached until five minutes before they expire.
+    fn managed_identity_token(&self, client_id: Option<&str>) -> Result<String, GitOpsError> {
+        let mut cached = self.token.lock().unwrap();
+        if let Some((token, expires)) = cached.as_ref() {
+            if Instant::now() + Duration::from_secs(300) < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let mut query = vec![
+            ("api-version", "2018-02-01"),
+            ("resource", "https://storage.azure.com/"),
+        ];
+        if let Some(client_id) = client_id {
+            query.push(("client_id", client_id));
+        }
+        let res = self
+            .client
+            .get(IMDS_TOKEN_URL)
+            .query(&query)
+            .header("Metadata", "true")
+            .send()
+            .map_err(GitOpsError::AzureNetworkError)?;
+        if !res.status().is_success() {
+            return Err(GitOpsError::AzureApiError(
+                IMDS_TOKEN_URL.to_owned(),
+                res.status(),
+                res.text()
+                    .unwrap_or("Metadata service returned unparseable error".to_owned()),
+            ));
+        }
+        let body: Value = res.json().map_err(GitOpsError::AzureNetworkError)?;
+        let token = body["access_token"]
+            .as_str()
+            .ok_or_else(|| {
+                GitOpsError::AzureConfig("no access token from metadata service".to_owned())
+            })?
+            .to_owned();
+        let expires_in = body["expires_in"]
+            .as_str()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(300);
+        *cached = Some((
+            token.clone(),
+            Instant::now() + Duration::from_secs(expires_in),
+        ));
+        Ok(token)
+    }
+
+    fn api_error(&self, res: Response) -> GitOpsError {
+        GitOpsError::AzureApiError(
+            self.url.clone(),
+            res.status(),
+            res.text()
+                .unwrap_or("Azure returned unparseable error".to_owned()),
+        )
+    }
+}
+
+/// State document stored as a block blob. Writes happen under a blob lease
+/// and merge in changes from other instances.
+pub struct AzureBlobStore {
+    blob: AzureBlob,
+    exists: bool,
+    state: HashMap<String, State>,
+}
+
+impl AzureBlobStore {
+    pub fn load(blob: AzureBlob) -> Result<Self, GitOpsError> {
+        let (state, exists) = match blob.get()? {
+            Some(body) => (deserialize_state(&body)?, true),
+            None => (HashMap::new(), false),
+        };
+        Ok(Self {
+            blob,
+            exists,
+            state,
+        })
+    }
+
+    fn acquire_lease(&self, id: &str) -> Result<String, GitOpsError> {
+        for _ in 0..LEASE_ATTEMPTS {
+            if let Some(lease_id) = self.blob.acquire_lease()? {
+                return Ok(lease_id);
+            }
+            sleep(LEASE_RETRY_DELAY);
+        }
+        Err(GitOpsError::StateConflict(id.to_owned()))
+    }
+
+    fn merge_and_write(
+        &mut self,
+        id: &str,
+        state: Option<&State>,
+        lease_id: &str,
+    ) -> Result<(), GitOpsError> {
+        if let Some(body) = self.blob.get()? {
+            self.state = deserialize_state(&body)?;
+        }
+        apply_update(&mut self.state, id, state);
+        self.blob.put(serialize_state(&self.state)?, lease_id)
+    }
+
+    /// Write our change under lease, on top of the current blob.
+    fn update(&mut self, id: &str, state: Option<&State>) -> Result<(), GitOpsError> {
+        if !self.exists {
+            self.blob.create_if_missing()?;
+            self.exists = true;
+        }
+        let lease_id = self.acquire_lease(id)?;
+        let res = self.merge_and_write(id, state, &lease_id);
+        // The lease expires on its own, so a failed release only delays other instances
+        let released = self.blob.release_lease(&lease_id);
+        res.and(released)
+    }
+}
+
+impl Store for AzureBlobStore {
+    fn get(&self, id: &str) -> Option<&State> {
+        self.state.get(id)
+    }
+
+    fn ids(&self) -> Vec<String> {
+        self.state.keys().cloned().collect()
+    }
+
+    fn retain(&mut self, task_ids: HashSet<String>) {
+        self.state.retain(|id, _| task_ids.contains(id));
+    }
+
+    fn persist(&mut self, id: String, state: &State) -> Result<(), GitOpsError> {
+        self.update(&id, Some(state))
+    }
+
+    fn remove(&mut self, id: &str) -> Result<(), GitOpsError> {
+        self.update(id, None)
+    }
+
+    fn reload(&mut self, id: &str) -> Result<Option<State>, GitOpsError> {
+        if let Some(body) = self.blob.get()? {
+            self.state = deserialize_state(&body)?;
+            self.exists = true;
+        }
+        Ok(self.state.get(id).cloned())
+    }
+}
+
+/// See "Authorize with Shared Key" in the Azure Storage REST API docs. Only
+/// covers the headers and query parameters that kitops sends.
+fn string_to_sign(
+    account: &str,
+    path: &str,
+    method: &str,
+    comp: Option<&str>,
+    headers: &[(&str, String)],
+    content_length: usize,
+) -> String {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map_or("", |(_, value)| value.as_str())
+    };
+    let mut ms_headers = headers
+        .iter()
+        .filter(|(name, _)| name.starts_with("x-ms-"))
+        .collect::<Vec<_>>();
+    ms_headers.sort();
+    let canonical_headers = ms_headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect::<String>();
+    let mut canonical_resource = format!("/{}{}", account, path);
+    if let Some(comp) = comp {
+        canonical_resource.push_str(&format!("\ncomp:{}", comp));
+    }
+    let content_length = if content_length == 0 {
+        String::new()
+    } else {
+        content_length.to_string()
+    };
+    // Verb, encoding, language, length, MD5, type, date, if-modified-since,
+    // if-match, if-none-match, if-unmodified-since, range
+    format!(
+        "{}\n\n\n{}\n\n\n\n\n{}\n{}\n\n\n{}{}",
+        method,
+        content_length,
+        header("if-match"),
+        header("if-none-match"),
+        canonical_headers,
+        canonical_resource
+    )
+}
+
+fn shared_key_signature(key: &str, string_to_sign: &str) -> Result<String, GitOpsError> {
+    let key = STANDARD
+        .decode(key)
+        .map_err(|err| GitOpsError::AzureConfig(format!("malformed account key: {}", err)))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts any key length");
+    mac.update(string_to_sign.as_bytes());
+    Ok(STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use gix::{hash::Kind, ObjectId};
+
+    use crate::{
+        errors::GitOpsError,
+        state::State,
+        store::Store,
+        testutils::{fake_server, FakeResponse},
+    };
+
+    use super::{shared_key_signature, string_to_sign, AzureAuth, AzureBlob, AzureBlobStore};
+
+    /// Azurite's well-known development account key
+    const DEV_KEY: &str =
+        "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+    #[derive(Default)]
+    struct FakeBlob {
+        content: Option<Vec<u8>>,
+        lease: Option<String>,
+        leases: u32,
+    }
+
+    /// Stand-in for Azurite holding a single blob with leases. Writes must
+    /// carry the active lease, if any. Signatures must cover what was sent.
+    fn fake_azurite() -> (String, Arc<Mutex<FakeBlob>>) {
+        let blob = Arc::new(Mutex::new(FakeBlob::default()));
+        let handle = blob.clone();
+        let endpoint = fake_server(move |req| {
+            let mut blob = handle.lock().unwrap();
+            let signed_headers = req
+                .headers
+                .iter()
+                .filter(|(name, _)| {
+                    name.starts_with("x-ms-") || *name == "if-match" || *name == "if-none-match"
+                })
+                .map(|(name, value)| (name.as_str(), value.clone()))
+                .collect::<Vec<_>>();
+            let (path, query) = req.target.split_once('?').unwrap_or((&req.target, ""));
+            let string_to_sign = string_to_sign(
+                "devstoreaccount1",
+                path,
+                &req.method,
+                query.strip_prefix("comp="),
+                &signed_headers,
+                req.body.len(),
+            );
+            assert_eq!(
+                req.headers["authorization"],
+                format!(
+                    "SharedKey devstoreaccount1:{}",
+                    shared_key_signature(DEV_KEY, &string_to_sign).unwrap()
+                )
+            );
+            let lease_id = req.headers.get("x-ms-lease-id");
+            match (req.method.as_str(), req.target.ends_with("comp=lease")) {
+                ("GET", _) => match &blob.content {
+                    Some(content) => FakeResponse::new("200 OK").with_body(content.clone()),
+                    None => FakeResponse::new("404 Not Found"),
+                },
+                ("PUT", true) => match req.headers["x-ms-lease-action"].as_str() {
+                    "acquire" if blob.lease.is_none() => {
+                        blob.leases += 1;
+                        let id = format!("lease-{}", blob.leases);
+                        blob.lease = Some(id.clone());
+                        FakeResponse::new("201 Created").with_header("x-ms-lease-id", id)
+                    }
+                    "release" if blob.lease.as_ref() == lease_id => {
+                        blob.lease = None;
+                        FakeResponse::new("200 OK")
+                    }
+                    _ => FakeResponse::new("409 Conflict"),
+                },
+                ("PUT", false)
+                    if req.headers.contains_key("if-none-match") && blob.content.is_some() =>
+                {
+                    FakeResponse::new("409 Conflict")
+                }
+                ("PUT", false) if blob.lease.as_ref() == lease_id => {
+                    blob.content = Some(req.body);
+                    FakeResponse::new("201 Created")
+                }
+                _ => FakeResponse::new("412 Precondition Failed"),
+            }
+        });
+        (format!("{}/devstoreaccount1", endpoint), blob)
+    }
+
+    fn store(endpoint: &str) -> AzureBlobStore {
+        let blob = AzureBlob::new(
+            endpoint,
+            "devstoreaccount1",
+            "kitops",
+            "state.yaml",
+            AzureAuth::SharedKey(DEV_KEY.to_owned()),
+        )
+        .unwrap();
+        AzureBlobStore::load(blob).unwrap()
+    }
+
+    fn state() -> State {
+        State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            ..Default::default()
+        }
+    }
+
+    // Expected signatures computed independently with openssl dgst -hmac
+    #[test]
+    fn shared_key_signature_known_answer() {
+        let headers = [
+            ("x-ms-version", "2009-09-19".to_owned()),
+            ("x-ms-date", "Sun, 11 Oct 2009 21:49:13 GMT".to_owned()),
+        ];
+        let string_to_sign = string_to_sign(
+            "devstoreaccount1",
+            "/devstoreaccount1/kitops/state.yaml",
+            "GET",
+            None,
+            &headers,
+            0,
+        );
+        assert_eq!(
+            string_to_sign,
+            "GET\n\n\n\n\n\n\n\n\n\n\n\n\
+             x-ms-date:Sun, 11 Oct 2009 21:49:13 GMT\n\
+             x-ms-version:2009-09-19\n\
+             /devstoreaccount1/devstoreaccount1/kitops/state.yaml"
+        );
+        assert_eq!(
+            shared_key_signature(DEV_KEY, &string_to_sign).unwrap(),
+            "d+W8fxeeHciqZEnG7bAKH7e6CIvh2MNA+Csy7lyQ0A0="
+        );
+    }
+
+    #[test]
+    fn shared_key_signature_covers_length_and_conditions() {
+        let headers = [
+            ("x-ms-version", "2021-08-06".to_owned()),
+            ("if-match", "\"0x8D\"".to_owned()),
+            ("x-ms-lease-id", "ze-lease".to_owned()),
+            ("x-ms-date", "Sun, 11 Oct 2009 21:49:13 GMT".to_owned()),
+            ("x-ms-blob-type", "BlockBlob".to_owned()),
+        ];
+        let string_to_sign = string_to_sign(
+            "devstoreaccount1",
+            "/devstoreaccount1/kitops/state.yaml",
+            "PUT",
+            None,
+            &headers,
+            7,
+        );
+        assert_eq!(
+            string_to_sign,
+            "PUT\n\n\n7\n\n\n\n\n\"0x8D\"\n\n\n\n\
+             x-ms-blob-type:BlockBlob\n\
+             x-ms-date:Sun, 11 Oct 2009 21:49:13 GMT\n\
+             x-ms-lease-id:ze-lease\n\
+             x-ms-version:2021-08-06\n\
+             /devstoreaccount1/devstoreaccount1/kitops/state.yaml"
+        );
+        assert_eq!(
+            shared_key_signature(DEV_KEY, &string_to_sign).unwrap(),
+            "E14r71AF4+EYnUXF/d/riOeBIF5nliLIpdoVVpbLYbs="
+        );
+    }
+
+    #[test]
+    fn azure_blob_path_includes_account_for_azurite() {
+        let blob = AzureBlob::new(
+            "http://127.0.0.1:10000/devstoreaccount1",
+            "devstoreaccount1",
+            "kitops",
+            "state.yaml",
+            AzureAuth::Sas("?sv=x".to_owned()),
+        )
+        .unwrap();
+        assert_eq!(blob.path, "/devstoreaccount1/kitops/state.yaml");
+    }
+
+    #[test]
+    fn azure_blob_store_roundtrip() {
+        let (endpoint, blob) = fake_azurite();
+        let mut first = store(&endpoint);
+        assert!(first.get("ze-task").is_none());
+        first.persist("ze-task".to_owned(), &state()).unwrap();
+        first.persist("ze-task".to_owned(), &state()).unwrap();
+        assert_eq!(blob.lock().unwrap().lease, None);
+        assert_eq!(blob.lock().unwrap().leases, 2);
+        let second = store(&endpoint);
+        assert!(second.get("ze-task").unwrap().current_sha.is_empty_blob());
+    }
+
+    #[test]
+    fn azure_blob_store_merges_other_instances() {
+        let (endpoint, _) = fake_azurite();
+        let mut first = store(&endpoint);
+        let mut second = store(&endpoint);
+        first.persist("first".to_owned(), &state()).unwrap();
+        second.persist("second".to_owned(), &state()).unwrap();
+        let third = store(&endpoint);
+        let ids = ["first", "second"]
+            .iter()
+            .map(|id| (*id, third.get(id).is_some()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(ids, HashMap::from([("first", true), ("second", true)]));
+    }
+
+    #[test]
+    fn azure_blob_store_gives_up_on_held_lease() {
+        let (endpoint, blob) = fake_azurite();
+        let mut store = store(&endpoint);
+        store.persist("ze-task".to_owned(), &state()).unwrap();
+        blob.lock().unwrap().lease = Some("other-instance".to_owned());
+        let started = Instant::now();
+        let res = store.persist("ze-task".to_owned(), &state());
+        assert!(matches!(res, Err(GitOpsError::StateConflict(id)) if id == "ze-task"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -1,9 +1,22 @@
-use std::{collections::HashMap, io::Read, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use cron::Schedule;
 use gix::Url;
 use serde::{Deserialize, Deserializer};
 
-use crate::{errors::GitOpsError, opts::CliOptions};
+use crate::{
+    errors::GitOpsError,
+    opts::CliOptions,
+    utils::{find_cycle, random_fraction},
+};
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -23,11 +36,40 @@ pub struct GitTaskConfig {
         deserialize_with = "human_readable_duration"
     )]
     pub interval: Duration,
+    /// Cron expression (UTC) for when to run; overrides interval
+    #[serde(default, deserialize_with = "optional_cron_schedule")]
+    pub schedule: Option<Schedule>,
+    /// Actions only run inside these windows; changes seen outside wait for the next one
+    #[serde(default)]
+    pub deploy_windows: Vec<DeployWindow>,
     #[serde(
         default = "GitTaskConfig::default_timeout",
         deserialize_with = "human_readable_duration"
     )]
     pub timeout: Duration,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// Names of tasks that must succeed before this task runs
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Run after actions succeed
+    #[serde(default)]
+    pub on_success: Vec<ActionConfig>,
+    /// Run after actions fail
+    #[serde(default)]
+    pub on_failure: Vec<ActionConfig>,
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+    /// Run after actions, whatever the outcome
+    #[serde(default)]
+    pub always: Vec<ActionConfig>,
+    /// Max number of actions running at the same time
+    #[serde(default = "GitTaskConfig::default_parallelism")]
+    pub parallelism: usize,
+    /// Decrypt SOPS files in the checkout before actions run
+    pub decrypt: Option<DecryptConfig>,
+    /// Only deploy commits signed by allowed keys
+    pub verify: Option<VerifyConfig>,
 }
 
 impl GitTaskConfig {
@@ -38,6 +80,51 @@ impl GitTaskConfig {
     pub fn default_timeout() -> Duration {
         Duration::from_secs(3600)
     }
+
+    pub fn default_parallelism() -> usize {
+        1
+    }
+
+    /// Check that each action list's needs refer to actions in the same list
+    /// and do not form cycles.
+    pub fn validate(&self) -> Result<(), GitOpsError> {
+        for actions in [
+            &self.actions[..],
+            &self.on_success,
+            &self.on_failure,
+            &self.always,
+        ] {
+            check_needs(&self.name, actions)?;
+        }
+        Ok(())
+    }
+
+    pub fn next_run(&self, now: SystemTime) -> SystemTime {
+        let scheduled = self
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.after(&DateTime::<Utc>::from(now)).next())
+            .map_or_else(|| now + self.interval, SystemTime::from);
+        // Also run as the next window opens, so that deferred changes are applied promptly
+        match self.next_deploy_window(now) {
+            Some(opens) if opens < scheduled => opens,
+            _ => scheduled,
+        }
+    }
+
+    pub fn in_deploy_window(&self, now: SystemTime) -> bool {
+        self.deploy_windows.is_empty() || self.deploy_windows.iter().any(|w| w.contains(now))
+    }
+
+    fn next_deploy_window(&self, now: SystemTime) -> Option<SystemTime> {
+        if self.in_deploy_window(now) {
+            return None;
+        }
+        self.deploy_windows
+            .iter()
+            .filter_map(|w| w.next_open(now))
+            .min()
+    }
 }
 
 impl TryFrom<&CliOptions> for GitTaskConfig {
@@ -52,11 +139,156 @@ impl TryFrom<&CliOptions> for GitTaskConfig {
             git: TryFrom::try_from(opts)?,
             actions: vec![action],
             interval: opts.interval.unwrap_or(Self::default_interval()),
+            schedule: None,
+            deploy_windows: Vec::new(),
             timeout: opts.timeout.unwrap_or(Self::default_timeout()),
+            retry: RetryConfig::default(),
+            depends_on: Vec::new(),
+            on_success: Vec::new(),
+            on_failure: Vec::new(),
+            failure_policy: FailurePolicy::default(),
+            always: Vec::new(),
+            parallelism: GitTaskConfig::default_parallelism(),
+            decrypt: None,
+            verify: None,
         })
     }
 }
 
+/// What to do with the target when actions fail.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Leave it as the failed actions left it
+    #[default]
+    Stop,
+    /// Re-run the actions for the last successful commit with KITOPS_ROLLBACK=1.
+    /// Once rolled back, the failed commit is not retried.
+    Rollback,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub fetch: RetryPolicy,
+    #[serde(default)]
+    pub actions: RetryPolicy,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Total number of attempts before falling back to the normal interval
+    pub max_attempts: Option<u32>,
+    /// Delay after the first failure; without it, failed runs wait for the next regular run
+    #[serde(default, deserialize_with = "optional_human_readable_duration")]
+    pub backoff_base: Option<Duration>,
+    #[serde(
+        default = "RetryPolicy::default_backoff_cap",
+        deserialize_with = "human_readable_duration"
+    )]
+    pub backoff_cap: Duration,
+    /// Fraction (0.0 - 1.0) of the delay that may be randomly shaved off
+    #[serde(default)]
+    pub jitter: f64,
+    /// Once attempts are exhausted, skip the failing SHA until a new commit arrives
+    #[serde(default)]
+    pub give_up: bool,
+}
+
+impl RetryPolicy {
+    pub fn default_backoff_cap() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        let base = self.backoff_base?;
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = base
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(self.backoff_cap);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        Some(delay.mul_f64(1.0 - jitter * random_fraction()))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            backoff_base: None,
+            backoff_cap: Self::default_backoff_cap(),
+            jitter: 0.0,
+            give_up: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeployWindow {
+    #[serde(default = "DeployWindow::all_days", deserialize_with = "weekdays")]
+    pub days: Vec<Weekday>,
+    #[serde(deserialize_with = "time_of_day")]
+    pub start: NaiveTime,
+    /// May be before start for windows that span midnight
+    #[serde(deserialize_with = "time_of_day")]
+    pub end: NaiveTime,
+    #[serde(
+        default = "DeployWindow::default_timezone",
+        deserialize_with = "timezone"
+    )]
+    pub timezone: Tz,
+}
+
+impl DeployWindow {
+    pub fn all_days() -> Vec<Weekday> {
+        vec![
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+            Weekday::Sun,
+        ]
+    }
+
+    pub fn default_timezone() -> Tz {
+        Tz::UTC
+    }
+
+    pub fn contains(&self, at: SystemTime) -> bool {
+        let local = DateTime::<Utc>::from(at).with_timezone(&self.timezone);
+        let (day, time) = (local.weekday(), local.time());
+        if self.start < self.end {
+            self.days.contains(&day) && self.start <= time && time < self.end
+        } else {
+            (self.days.contains(&day) && time >= self.start)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+
+    pub fn next_open(&self, after: SystemTime) -> Option<SystemTime> {
+        let local = DateTime::<Utc>::from(after).with_timezone(&self.timezone);
+        (0..=7)
+            .filter_map(|offset| {
+                local
+                    .date_naive()
+                    .checked_add_days(chrono::Days::new(offset))
+            })
+            .filter(|date| self.days.contains(&date.weekday()))
+            .filter_map(|date| {
+                self.timezone
+                    .from_local_datetime(&date.and_time(self.start))
+                    .earliest()
+            })
+            .map(SystemTime::from)
+            .find(|opens| *opens > after)
+    }
+}
+
 #[derive(Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct GithubConfig {
@@ -95,6 +327,22 @@ pub struct GitConfig {
     pub url: Url,
     #[serde(default = "GitConfig::default_branch")]
     pub branch: String,
+    /// What to do when the branch no longer descends from the deployed commit
+    #[serde(default)]
+    pub non_fast_forward: NonFastForwardPolicy,
+}
+
+/// Handling of rewritten history, e.g. after a force-push.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonFastForwardPolicy {
+    /// Deploy the new head as usual
+    #[default]
+    Allow,
+    /// Deploy the new head but raise an alert
+    Warn,
+    /// Keep the deployed commit and raise an alert until the branch descends from it again
+    Refuse,
 }
 
 impl GitConfig {
@@ -111,6 +359,7 @@ impl TryFrom<&CliOptions> for GitConfig {
         Ok(GitConfig {
             url,
             branch: opts.branch.clone(),
+            non_fast_forward: NonFastForwardPolicy::default(),
         })
     }
 }
@@ -124,8 +373,160 @@ pub struct ActionConfig {
     pub args: Vec<String>,
     #[serde(default)]
     pub environment: HashMap<String, String>,
+    /// Pass kitops' own environment to the action. Has no effect with `image`:
+    /// containers only see `environment` and secrets.
     #[serde(default)]
     pub inherit_environment: bool,
+    /// Max run time for this action; the task timeout still applies
+    #[serde(default, deserialize_with = "optional_human_readable_duration")]
+    pub timeout: Option<Duration>,
+    /// Time between SIGTERM and SIGKILL when the action times out
+    #[serde(
+        default = "ActionConfig::default_termination_grace_period",
+        deserialize_with = "human_readable_duration"
+    )]
+    pub termination_grace_period: Duration,
+    /// Carry on with the next action if this one fails
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// Times to re-run the action when it fails before giving up
+    #[serde(default)]
+    pub retries: u32,
+    /// Skip the action unless these conditions hold
+    pub when: Option<WhenConfig>,
+    /// Names of actions in the same list that must finish first
+    #[serde(default)]
+    pub needs: Vec<String>,
+    /// Run the entrypoint in a container from this image, with the workdir mounted
+    pub image: Option<String>,
+    /// Docker-compatible CLI used to run containers, e.g. podman
+    #[serde(default = "ActionConfig::default_container_runtime")]
+    pub container_runtime: String,
+    /// Isolate the action from the host; Linux only and requires root
+    pub sandbox: Option<SandboxConfig>,
+    /// Environment read when the action runs and redacted from its output
+    #[serde(default)]
+    pub secret_env: Vec<SecretEnvConfig>,
+}
+
+/// Where to read secret environment variables from.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum SecretEnvConfig {
+    /// Contents of a file, without trailing newline
+    File { name: String, path: PathBuf },
+    /// A variable in kitops' own environment, allowed with --secret-env-allow
+    Env { name: String, from: String },
+    /// Each file in a directory, e.g. a mounted Kubernetes secret, as a variable
+    /// named after the file in upper case with other characters than A-Z, 0-9 as _
+    Dir(PathBuf),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyConfig {
+    /// ssh allowed_signers file listing keys allowed to sign
+    pub allowed_signers: Option<PathBuf>,
+    /// GnuPG keyring holding the keys allowed to sign
+    pub gpg_keyring: Option<PathBuf>,
+    /// Further restrict GPG signatures to these (primary or sub) key fingerprints
+    #[serde(default)]
+    pub gpg_fingerprints: Vec<String>,
+    /// Verify every commit since the deployed one, not only the new head
+    #[serde(default)]
+    pub all_commits: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DecryptConfig {
+    /// age identity (private key) file handed to sops
+    pub age_identity: PathBuf,
+    /// Decrypt files whose names end with one of these
+    #[serde(default = "DecryptConfig::default_suffixes")]
+    pub suffixes: Vec<String>,
+    /// sops executable
+    #[serde(default = "DecryptConfig::default_sops")]
+    pub sops: String,
+}
+
+impl DecryptConfig {
+    pub fn default_suffixes() -> Vec<String> {
+        [".sops.yaml", ".sops.yml", ".sops.json", ".sops.env"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    pub fn default_sops() -> String {
+        "sops".to_owned()
+    }
+
+    /// A bare suffix is not a match, so that sops' creation rules in
+    /// .sops.yaml are left alone.
+    pub fn matches(&self, file_name: &str) -> bool {
+        self.suffixes
+            .iter()
+            .any(|suffix| file_name.len() > suffix.len() && file_name.ends_with(suffix.as_str()))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// Keep host networking; otherwise the action only has a loopback interface
+    #[serde(default)]
+    pub network: bool,
+    /// Numeric uid to run as
+    pub user: Option<u32>,
+    /// Numeric gid to run as; defaults to the user's primary group
+    pub group: Option<u32>,
+    /// Max address space in bytes for each process
+    pub memory_limit: Option<u64>,
+    /// Max CPU time for each process
+    #[serde(default, deserialize_with = "optional_human_readable_duration")]
+    pub cpu_time_limit: Option<Duration>,
+    /// Files and directories to make unreadable, in addition to the task's
+    /// repo cache, age identity and GitHub App key
+    #[serde(default)]
+    pub hide: Vec<PathBuf>,
+}
+
+/// Conditions for running an action; an empty list matches anything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WhenConfig {
+    /// Only run when the task tracks one of these branches
+    #[serde(default)]
+    pub branches: Vec<String>,
+    /// Only run when a file at or under one of these paths changed
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+impl WhenConfig {
+    /// Changed paths are None when there is no previous commit to compare with.
+    pub fn matches(&self, branch: &str, changed: Option<&[String]>) -> bool {
+        let branch_matches = self.branches.is_empty() || self.branches.iter().any(|b| b == branch);
+        let paths_match = self.paths.is_empty()
+            || changed.map_or(true, |changed| {
+                changed.iter().any(|path| {
+                    self.paths
+                        .iter()
+                        .any(|prefix| Path::new(path).starts_with(prefix))
+                })
+            });
+        branch_matches && paths_match
+    }
+}
+
+impl ActionConfig {
+    pub fn default_termination_grace_period() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn default_container_runtime() -> String {
+        "docker".to_owned()
+    }
 }
 
 impl TryFrom<&CliOptions> for ActionConfig {
@@ -146,6 +547,16 @@ impl TryFrom<&CliOptions> for ActionConfig {
             args: vec!["-c".to_string(), opts.action.clone().unwrap()],
             environment,
             inherit_environment: false,
+            timeout: None,
+            termination_grace_period: ActionConfig::default_termination_grace_period(),
+            continue_on_error: false,
+            retries: 0,
+            when: None,
+            needs: Vec::new(),
+            image: None,
+            container_runtime: ActionConfig::default_container_runtime(),
+            sandbox: None,
+            secret_env: Vec::new(),
         })
     }
 }
@@ -158,6 +569,58 @@ where
     humantime::parse_duration(&s).map_err(serde::de::Error::custom)
 }
 
+fn optional_human_readable_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    s.map(|s| humantime::parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn optional_cron_schedule<'de, D>(deserializer: D) -> Result<Option<Schedule>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    s.map(|s| {
+        // The cron crate wants a seconds field; accept classic five-field expressions too
+        let expr = if s.split_whitespace().count() == 5 {
+            format!("0 {}", s)
+        } else {
+            s
+        };
+        Schedule::from_str(&expr).map_err(serde::de::Error::custom)
+    })
+    .transpose()
+}
+
+fn weekdays<'de, D>(deserializer: D) -> Result<Vec<Weekday>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let days: Vec<String> = Deserialize::deserialize(deserializer)?;
+    days.iter()
+        .map(|d| d.parse::<Weekday>().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn time_of_day<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+fn timezone<'de, D>(deserializer: D) -> Result<Tz, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    s.parse::<Tz>().map_err(serde::de::Error::custom)
+}
+
 fn url_from_string<'de, D>(deserializer: D) -> Result<Url, D::Error>
 where
     D: Deserializer<'de>,
@@ -166,15 +629,61 @@ where
     Url::try_from(s).map_err(serde::de::Error::custom)
 }
 
+fn check_needs(task: &str, actions: &[ActionConfig]) -> Result<(), GitOpsError> {
+    let indices = actions
+        .iter()
+        .enumerate()
+        .map(|(idx, a)| (a.name.as_str(), idx))
+        .collect::<HashMap<_, _>>();
+    let needs = actions
+        .iter()
+        .map(|a| {
+            a.needs
+                .iter()
+                .map(|need| {
+                    indices.get(need.as_str()).copied().ok_or_else(|| {
+                        GitOpsError::UnknownActionDependency(
+                            task.to_owned(),
+                            a.name.clone(),
+                            need.clone(),
+                        )
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(idx) = find_cycle(&needs) {
+        return Err(GitOpsError::ActionDependencyCycle(
+            task.to_owned(),
+            actions[idx].name.clone(),
+        ));
+    }
+    Ok(())
+}
+
 pub fn read_config(reader: impl Read) -> Result<ConfigFile, GitOpsError> {
-    serde_yaml::from_reader(reader).map_err(GitOpsError::MalformedConfig)
+    let config: ConfigFile =
+        serde_yaml::from_reader(reader).map_err(GitOpsError::MalformedConfig)?;
+    for task in &config.tasks {
+        task.validate()?;
+    }
+    Ok(config)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::time::{Duration, SystemTime};
+
+    use chrono::{TimeZone, Utc};
 
-    use crate::{config::GitTaskConfig, errors::GitOpsError};
+    use crate::{
+        config::{DeployWindow, FailurePolicy, GitTaskConfig, RetryPolicy, WhenConfig},
+        errors::GitOpsError,
+    };
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> SystemTime {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap().into()
+    }
 
     use super::read_config;
 
@@ -253,4 +762,247 @@ actions: []
         assert_eq!(config.timeout, Duration::from_secs(3));
         assert_eq!(config.interval, Duration::from_secs(62));
     }
+
+    #[test]
+    fn parse_retry_config() {
+        let raw_config = r#"name: testo
+git:
+  url: https://github.com/bittrance/kitops
+retry:
+  fetch:
+    max_attempts: 5
+    backoff_base: 10s
+    backoff_cap: 1m
+  actions:
+    max_attempts: 3
+    give_up: true
+actions: []
+"#;
+        let config = serde_yaml::from_str::<GitTaskConfig>(raw_config).unwrap();
+        assert_eq!(config.retry.fetch.max_attempts, Some(5));
+        assert_eq!(
+            config.retry.fetch.backoff_base,
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(config.retry.fetch.backoff_cap, Duration::from_secs(60));
+        assert!(config.retry.actions.give_up);
+        assert_eq!(config.retry.actions.backoff_base, None);
+    }
+
+    #[test]
+    fn retry_backoff_grows_to_cap() {
+        let policy = RetryPolicy {
+            backoff_base: Some(Duration::from_secs(10)),
+            backoff_cap: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Some(Duration::from_secs(10)));
+        assert_eq!(policy.backoff(2), Some(Duration::from_secs(20)));
+        assert_eq!(policy.backoff(3), Some(Duration::from_secs(40)));
+        assert_eq!(policy.backoff(4), Some(Duration::from_secs(60)));
+        assert_eq!(policy.backoff(100), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn retry_backoff_without_base_uses_regular_schedule() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(3), None);
+    }
+
+    #[test]
+    fn retry_backoff_with_jitter_stays_below_delay() {
+        let policy = RetryPolicy {
+            backoff_base: Some(Duration::from_secs(10)),
+            jitter: 0.5,
+            ..Default::default()
+        };
+        let delay = policy.backoff(1).unwrap();
+        assert!(delay <= Duration::from_secs(10));
+        assert!(delay >= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn parse_schedule_and_deploy_windows() {
+        let raw_config = r#"name: testo
+git:
+  url: https://github.com/bittrance/kitops
+schedule: "*/15 * * * *"
+deploy_windows:
+  - days: [mon, tue, wed, thu, fri]
+    start: "09:00"
+    end: "16:00"
+    timezone: Europe/Stockholm
+actions: []
+"#;
+        let config = serde_yaml::from_str::<GitTaskConfig>(raw_config).unwrap();
+        assert!(config.schedule.is_some());
+        assert_eq!(config.deploy_windows[0].days.len(), 5);
+        assert_eq!(
+            config.deploy_windows[0].timezone,
+            chrono_tz::Europe::Stockholm
+        );
+    }
+
+    #[test]
+    fn fail_on_bad_cron_schedule() {
+        let raw_config = r#"name: testo
+git:
+  url: https://github.com/bittrance/kitops
+schedule: "every now and then"
+actions: []
+"#;
+        assert!(serde_yaml::from_str::<GitTaskConfig>(raw_config).is_err());
+    }
+
+    #[test]
+    fn next_run_follows_cron_schedule() {
+        let raw_config = r#"name: testo
+git:
+  url: https://github.com/bittrance/kitops
+schedule: "0 3 * * *"
+actions: []
+"#;
+        let config = serde_yaml::from_str::<GitTaskConfig>(raw_config).unwrap();
+        let next = config.next_run(utc(2024, 3, 15, 17, 0));
+        assert_eq!(next, utc(2024, 3, 16, 3, 0));
+    }
+
+    fn office_hours() -> DeployWindow {
+        serde_yaml::from_str(
+            r#"
+days: [mon, tue, wed, thu, fri]
+start: "09:00"
+end: "16:00"
+timezone: Europe/Stockholm
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn deploy_window_contains() {
+        let window = office_hours();
+        // Friday 2024-03-15, Stockholm is UTC+1
+        assert!(window.contains(utc(2024, 3, 15, 8, 0)));
+        assert!(!window.contains(utc(2024, 3, 15, 15, 0)));
+        assert!(!window.contains(utc(2024, 3, 16, 10, 0)));
+    }
+
+    #[test]
+    fn deploy_window_opens_after_weekend() {
+        let window = office_hours();
+        let opens = window.next_open(utc(2024, 3, 15, 17, 0));
+        assert_eq!(opens, Some(utc(2024, 3, 18, 8, 0)));
+    }
+
+    #[test]
+    fn deploy_window_across_midnight() {
+        let window: DeployWindow = serde_yaml::from_str(
+            r#"
+days: [fri]
+start: "22:00"
+end: "02:00"
+"#,
+        )
+        .unwrap();
+        assert!(window.contains(utc(2024, 3, 15, 23, 0)));
+        assert!(window.contains(utc(2024, 3, 16, 1, 0)));
+        assert!(!window.contains(utc(2024, 3, 16, 3, 0)));
+        assert!(!window.contains(utc(2024, 3, 14, 23, 0)));
+    }
+
+    #[test]
+    fn next_run_moves_up_to_deploy_window() {
+        let raw_config = r#"name: testo
+git:
+  url: https://github.com/bittrance/kitops
+interval: 1h
+deploy_windows:
+  - start: "09:00"
+    end: "16:00"
+actions: []
+"#;
+        let config = serde_yaml::from_str::<GitTaskConfig>(raw_config).unwrap();
+        assert_eq!(
+            config.next_run(utc(2024, 3, 15, 8, 30)),
+            utc(2024, 3, 15, 9, 0)
+        );
+        assert_eq!(
+            config.next_run(utc(2024, 3, 15, 10, 30)),
+            utc(2024, 3, 15, 11, 30)
+        );
+    }
+
+    #[test]
+    fn failure_policy_alongside_failure_hooks() {
+        let raw_config = r#"name: testo
+git:
+  url: https://github.com/bittrance/kitops
+actions: []
+"#;
+        let config = serde_yaml::from_str::<GitTaskConfig>(raw_config).unwrap();
+        assert_eq!(config.failure_policy, FailurePolicy::Stop);
+        assert!(config.on_failure.is_empty());
+        let raw_config = r#"name: testo
+git:
+  url: https://github.com/bittrance/kitops
+actions: []
+failure_policy: rollback
+on_failure:
+  - name: page on-call
+    entrypoint: /bin/true
+"#;
+        let config = serde_yaml::from_str::<GitTaskConfig>(raw_config).unwrap();
+        assert_eq!(config.failure_policy, FailurePolicy::Rollback);
+        assert_eq!(config.on_failure[0].name, "page on-call");
+    }
+
+    #[test]
+    fn when_matches_branch_and_paths() {
+        let when: WhenConfig =
+            serde_yaml::from_str("{branches: [main], paths: [deploy/, charts/app]}").unwrap();
+        let changed = vec!["charts/app/values.yaml".to_owned()];
+        assert!(when.matches("main", Some(&changed)));
+        assert!(when.matches("main", None));
+        assert!(!when.matches("develop", Some(&changed)));
+        assert!(!when.matches("main", Some(&["deployment.yaml".to_owned()])));
+        assert!(!when.matches("main", Some(&[])));
+    }
+
+    #[test]
+    fn refuse_action_needs_cycle() {
+        let config = r#"tasks:
+  - name: testo
+    git:
+      url: https://github.com/bittrance/kitops
+    actions:
+      - name: first
+        entrypoint: /bin/ls
+        needs: [second]
+      - name: second
+        entrypoint: /bin/ls
+        needs: [first]
+"#;
+        assert!(matches!(
+            read_config(config.as_bytes()),
+            Err(GitOpsError::ActionDependencyCycle(..))
+        ));
+    }
+
+    #[test]
+    fn refuse_unknown_action_need() {
+        let config = r#"tasks:
+  - name: testo
+    git:
+      url: https://github.com/bittrance/kitops
+    actions:
+      - name: first
+        entrypoint: /bin/ls
+        needs: [nonesuch]
+"#;
+        assert!(matches!(
+            read_config(config.as_bytes()),
+            Err(GitOpsError::UnknownActionDependency(..))
+        ));
+    }
 }
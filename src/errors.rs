@@ -1,5 +1,6 @@
 use std::{fmt::Debug, path::PathBuf};
 
+use gix::ObjectId;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -16,6 +17,20 @@ pub enum GitOpsError {
     ConfigMethodConflict,
     #[error("Provide --interval or --once-only")]
     ConfigExecutionConflict,
+    #[error("Task {0} depends on unknown task {1}")]
+    UnknownDependency(String, String),
+    #[error("Task {0} depends on itself through depends_on")]
+    DependencyCycle(String),
+    #[error("Action {1} in task {0} needs unknown action {2}")]
+    UnknownActionDependency(String, String, String),
+    #[error("Action {1} in task {0} needs itself through needs")]
+    ActionDependencyCycle(String, String),
+    #[error("Failed to resolve secret_env: {0}")]
+    SecretEnv(String),
+    #[error("No state recorded for task {0}")]
+    UnknownTask(String),
+    #[error("Not a valid commit SHA: {0}")]
+    InvalidSha(String),
     #[error("Notify section needs github_repo_slug and github_context")]
     InvalidNotifyConfig,
     #[error("Cannot find directory to store repositories: {0}")]
@@ -30,20 +45,70 @@ pub enum GitOpsError {
     SavingState(std::io::Error),
     #[error("Failed to de/serialize state: {0}")]
     SerdeState(serde_yaml::Error),
+    #[error("State was written by a newer kitops (version {0})")]
+    UnsupportedStateVersion(u64),
+    #[error("State URL must be file://, s3://, azblob:// or kubernetes:// (see --help): {0}")]
+    InvalidStateUrl(String),
+    #[error("S3 state store not configured: {0}")]
+    S3Config(String),
+    #[error("S3 request to {0} returned status {1}: {2}")]
+    S3ApiError(String, reqwest::StatusCode, String),
+    #[error("Failed to connect to S3: {0}")]
+    S3NetworkError(reqwest::Error),
+    #[error("Azure Blob state store not configured: {0}")]
+    AzureConfig(String),
+    #[error("Azure request to {0} returned status {1}: {2}")]
+    AzureApiError(String, reqwest::StatusCode, String),
+    #[error("Failed to connect to Azure: {0}")]
+    AzureNetworkError(reqwest::Error),
+    #[error("State for {0} kept being changed by other instances")]
+    StateConflict(String),
     #[error("Failed to create or locate workdir: {0}")]
     WorkDir(std::io::Error),
     #[error("Failed to create new repository: {0}")]
-    InitRepo(gix::clone::fetch::Error),
+    InitRepo(Box<dyn std::error::Error + Send + Sync>),
     #[error("Failed to connect to remote: {0}")]
     FetchError(Box<dyn std::error::Error + Send + Sync>),
     #[error("Failed to open repository: {0}")]
     OpenRepo(gix::open::Error),
-    #[error("Action failed: {1} in {0}")]
-    ActionFailed(String, String),
+    #[error("Branch not found: {0}")]
+    MissingBranch(String),
+    #[error("Repository is corrupt or inaccessible: {0}")]
+    CorruptRepo(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Failed to check out worktree: {0}")]
+    CheckoutFailed(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Commit {0} failed signature verification: {1}")]
+    UnverifiedCommit(ObjectId, String),
+    #[error("Failed to decrypt {0}: {1}")]
+    DecryptFailed(PathBuf, String),
+    #[error("Action failed: {1} in {0} for {2}")]
+    ActionFailed(String, String, ObjectId),
+    #[error("{1}; rolled back from {0}")]
+    RolledBack(ObjectId, Box<GitOpsError>),
+    #[error("Task worker panicked: {0}")]
+    WorkerPanic(String),
     #[error("Failed to send event: {0}")]
     NotifyError(String),
     #[error("Failed to launch action: {0}")]
     ActionError(std::io::Error),
+    #[error("Shutting down before action {0} could run")]
+    ActionInterrupted(String),
+    #[error("Failed to install signal handler: {0}")]
+    SignalHandler(ctrlc::Error),
+    #[error("Shutdown grace period expired with {0} task(s) still running")]
+    ShutdownTimeout(usize),
+    #[error("Lease URL must be file:///<dir> or kubernetes://[<namespace>]: {0}")]
+    InvalidLeaseUrl(String),
+    #[error("File leases need flock, which this platform lacks: {0}")]
+    UnsupportedLease(String),
+    #[error("Failed to read/write lease file: {0}")]
+    LeaseFile(std::io::Error),
+    #[error("Not running in Kubernetes or service account not mounted: {0}")]
+    KubernetesConfig(String),
+    #[error("Kubernetes API {0} returned status {1}: {2}")]
+    KubernetesApiError(String, reqwest::StatusCode, String),
+    #[error("Failed to connect to Kubernetes API: {0}")]
+    KubernetesNetworkError(reqwest::Error),
     #[error("Auth only on HTTPS URLs: {0}")]
     GitHubAuthNonHttpsUrl(String),
     #[error("Missing private key file: {0}")]
@@ -61,13 +126,150 @@ pub enum GitOpsError {
     TestError,
 }
 
+/// How an error affects the daemon as a whole.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+    /// Bad configuration or startup environment; the task cannot succeed until fixed
+    Config,
+    /// Affects a single run; reported via events and retried
+    Transient,
+    /// kitops cannot continue safely, e.g. because state cannot be persisted
+    Fatal,
+}
+
 impl GitOpsError {
-    #[allow(clippy::unused_self)]
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::InvalidUrl(..)
+            | Self::InvalidEnvVar(..)
+            | Self::MissingConfig(..)
+            | Self::MalformedConfig(..)
+            | Self::ConfigMethodConflict
+            | Self::ConfigExecutionConflict
+            | Self::InvalidNotifyConfig
+            | Self::UnknownDependency(..)
+            | Self::DependencyCycle(..)
+            | Self::UnknownActionDependency(..)
+            | Self::ActionDependencyCycle(..)
+            | Self::SecretEnv(..)
+            | Self::UnknownTask(..)
+            | Self::InvalidSha(..)
+            | Self::InvalidLeaseUrl(..)
+            | Self::UnsupportedLease(..)
+            | Self::InvalidStateUrl(..)
+            | Self::S3Config(..)
+            | Self::AzureConfig(..)
+            | Self::KubernetesConfig(..)
+            | Self::MissingRepoDir(..)
+            | Self::MissingBranch(..)
+            | Self::GitHubAuthNonHttpsUrl(..)
+            | Self::GitHubMissingPrivateKeyFile(..)
+            | Self::GitHubBadPrivateKey(..)
+            | Self::GitHubPermissionsError => ErrorClass::Config,
+            Self::InitRepo(..)
+            | Self::FetchError(..)
+            | Self::OpenRepo(..)
+            | Self::CorruptRepo(..)
+            | Self::CheckoutFailed(..)
+            | Self::DecryptFailed(..)
+            | Self::UnverifiedCommit(..)
+            | Self::WorkerPanic(..)
+            | Self::ActionFailed(..)
+            | Self::RolledBack(..)
+            | Self::NotifyError(..)
+            | Self::ActionError(..)
+            | Self::ActionInterrupted(..)
+            | Self::GitHubApiError(..)
+            | Self::GitHubNetworkError(..)
+            | Self::LeaseFile(..)
+            | Self::KubernetesApiError(..)
+            | Self::KubernetesNetworkError(..)
+            | Self::S3ApiError(..)
+            | Self::S3NetworkError(..)
+            | Self::AzureApiError(..)
+            | Self::AzureNetworkError(..)
+            | Self::StateConflict(..)
+            | Self::WorkDir(..) => ErrorClass::Transient,
+            Self::CreateRepoDir(..)
+            | Self::StateFile(..)
+            | Self::LoadingState(..)
+            | Self::SavingState(..)
+            | Self::SerdeState(..)
+            | Self::UnsupportedStateVersion(..)
+            | Self::SignalHandler(..)
+            | Self::ShutdownTimeout(..) => ErrorClass::Fatal,
+            #[cfg(test)]
+            Self::TestError => ErrorClass::Transient,
+        }
+    }
+
+    /// Fatal errors stop all tasks; other errors only fail the current run.
     pub fn is_fatal(&self) -> bool {
-        #[allow(clippy::match_like_matches_macro)]
+        self.class() == ErrorClass::Fatal
+    }
+
+    /// Failures that happened while running actions, as opposed to fetching.
+    pub fn is_action_failure(&self) -> bool {
+        matches!(
+            self,
+            Self::ActionFailed(..)
+                | Self::ActionError(..)
+                | Self::ActionInterrupted(..)
+                | Self::RolledBack(..)
+        )
+    }
+
+    /// The commit whose actions failed, for failures that know it.
+    pub fn failed_sha(&self) -> Option<ObjectId> {
         match self {
-            Self::ActionFailed(..) => false,
-            _ => true,
+            Self::ActionFailed(_, _, sha) | Self::RolledBack(sha, _) => Some(*sha),
+            _ => None,
         }
     }
+
+    /// Process exit code when this error stops kitops.
+    pub fn exit_code(&self) -> u8 {
+        match (self, self.class()) {
+            (Self::ShutdownTimeout(..), _) => 3,
+            (_, ErrorClass::Config) => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gix::{hash::Kind, ObjectId};
+
+    use super::{ErrorClass, GitOpsError};
+
+    #[test]
+    fn run_errors_are_not_fatal() {
+        let fetch = GitOpsError::FetchError("connection reset".into());
+        assert_eq!(fetch.class(), ErrorClass::Transient);
+        assert!(!fetch.is_fatal());
+        let action = GitOpsError::ActionFailed(
+            "ze-task".to_owned(),
+            "ze-action".to_owned(),
+            ObjectId::null(Kind::Sha1),
+        );
+        assert!(!action.is_fatal());
+        assert!(!GitOpsError::GitHubPermissionsError.is_fatal());
+        let store = GitOpsError::S3ApiError(
+            "https://example.com/bucket/state.yaml".to_owned(),
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            "SlowDown".to_owned(),
+        );
+        assert_eq!(store.class(), ErrorClass::Transient);
+        let workdir = GitOpsError::WorkDir(std::io::Error::from(std::io::ErrorKind::Other));
+        assert_eq!(workdir.class(), ErrorClass::Transient);
+    }
+
+    #[test]
+    fn state_errors_are_fatal() {
+        let err =
+            GitOpsError::SavingState(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert_eq!(err.class(), ErrorClass::Fatal);
+        assert!(err.is_fatal());
+    }
 }
@@ -207,6 +207,42 @@ pub fn github_watcher(
                     &format!("running {} [last success {}]", name, prev_sha),
                 )?;
             }
+            WorkloadEvent::Deferred(name, new_sha) => {
+                update_commit_status(
+                    &repo_slug,
+                    &config,
+                    &new_sha,
+                    GitHubStatus::Pending,
+                    &format!("{} waiting for deploy window", name),
+                )?;
+            }
+            WorkloadEvent::AwaitingDependencies(name, new_sha) => {
+                update_commit_status(
+                    &repo_slug,
+                    &config,
+                    &new_sha,
+                    GitHubStatus::Pending,
+                    &format!("{} waiting for dependencies", name),
+                )?;
+            }
+            WorkloadEvent::NonFastForward(name, _, new_sha, true) => {
+                update_commit_status(
+                    &repo_slug,
+                    &config,
+                    &new_sha,
+                    GitHubStatus::Failure,
+                    &format!("{} refused commit: not a fast-forward", name),
+                )?;
+            }
+            WorkloadEvent::Unverified(name, sha, _) => {
+                update_commit_status(
+                    &repo_slug,
+                    &config,
+                    &sha,
+                    GitHubStatus::Failure,
+                    &format!("{} refused commit: signature not verified", name),
+                )?;
+            }
             WorkloadEvent::Success(name, new_sha) => {
                 update_commit_status(
                     &repo_slug,
@@ -234,6 +270,19 @@ pub fn github_watcher(
                     &format!("{} errored on action {}", task, action),
                 )?;
             }
+            WorkloadEvent::RolledBack(task, failed_sha, target_sha, reason) => {
+                let (status, description) = match reason {
+                    None => (
+                        GitHubStatus::Failure,
+                        format!("{} failed; rolled back to {}", task, target_sha),
+                    ),
+                    Some(_) => (
+                        GitHubStatus::Error,
+                        format!("{} failed; rollback to {} failed too", task, target_sha),
+                    ),
+                };
+                update_commit_status(&repo_slug, &config, &failed_sha, status, &description)?;
+            }
             _ => (),
         };
         Ok(())
@@ -1,5 +1,6 @@
 use std::{
     cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
     path::Path,
     sync::{atomic::AtomicBool, Arc},
     thread::scope,
@@ -21,6 +22,7 @@ use gix::{
         Target,
     },
     remote::{fetch::Outcome, ref_map::Options, Direction},
+    traverse::tree::Recorder,
     ObjectId, Repository, Url,
 };
 
@@ -57,19 +59,26 @@ impl UrlProvider for DefaultUrlProvider {
     }
 }
 
+fn prepare_clone(url: Url, target: &Path) -> Result<gix::clone::PrepareFetch, GitOpsError> {
+    let no_prompt = gitoxide::Credentials::TERMINAL_PROMPT
+        .validated_assignment_fmt(&false)
+        .map_err(|err| GitOpsError::InitRepo(Box::new(err)))?;
+    Ok(gix::prepare_clone(url, target)
+        .map_err(|err| GitOpsError::InitRepo(Box::new(err)))?
+        .with_in_memory_config_overrides(vec![no_prompt]))
+}
+
 // TODO What about branch?!
 fn clone_repo(url: Url, deadline: Instant, target: &Path) -> Result<Repository, GitOpsError> {
     let watchdog = Watchdog::new(deadline);
     scope(|s| {
         s.spawn(watchdog.runner());
-        let maybe_repo = gix::prepare_clone(url, target)
-            .unwrap()
-            .with_in_memory_config_overrides(vec![gitoxide::Credentials::TERMINAL_PROMPT
-                .validated_assignment_fmt(&false)
-                .unwrap()])
-            .fetch_only(Discard, &watchdog)
-            .map(|(r, _)| r)
-            .map_err(GitOpsError::InitRepo);
+        let maybe_repo = prepare_clone(url, target).and_then(|mut clone| {
+            clone
+                .fetch_only(Discard, &watchdog)
+                .map(|(r, _)| r)
+                .map_err(|err| GitOpsError::InitRepo(Box::new(err)))
+        });
         watchdog.cancel();
         maybe_repo
     })
@@ -81,10 +90,8 @@ fn perform_fetch(
     branch: &str,
     cancel: &AtomicBool,
 ) -> Result<Outcome, Box<dyn std::error::Error + Send + Sync>> {
-    repo.remote_at(url)
-        .unwrap()
-        .with_refspecs([BString::from(branch)], Direction::Fetch)
-        .unwrap()
+    repo.remote_at(url)?
+        .with_refspecs([BString::from(branch)], Direction::Fetch)?
         .connect(Direction::Fetch)?
         .prepare_fetch(Discard, Options::default())?
         .receive(Discard, cancel)
@@ -111,7 +118,7 @@ fn fetch_repo(
         .iter()
         .map(|r| r.unpack())
         .find_map(|(name, oid, _)| if name == needle.as_bstr() { oid } else { None })
-        .unwrap()
+        .ok_or_else(|| GitOpsError::MissingBranch(branch.to_owned()))?
         .to_owned();
     let edit = RefEdit {
         change: Change::Update {
@@ -123,10 +130,13 @@ fn fetch_repo(
             expected: gix::refs::transaction::PreviousValue::Any,
             new: Target::Peeled(target),
         },
-        name: needle.try_into().unwrap(),
+        name: needle
+            .try_into()
+            .map_err(|_| GitOpsError::MissingBranch(branch.to_owned()))?,
         deref: false,
     };
-    repo.edit_reference(edit).unwrap();
+    repo.edit_reference(edit)
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
     Ok(())
 }
 
@@ -172,19 +182,37 @@ fn checkout_worktree(
 ) -> Result<ObjectId, GitOpsError> {
     let oid = repo
         .refs
-        .find(branch)
-        .unwrap()
+        .try_find(branch)
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?
+        .ok_or_else(|| GitOpsError::MissingBranch(branch.to_owned()))?
         .target
         .try_into_id()
-        .unwrap();
+        .map_err(|target| {
+            GitOpsError::CorruptRepo(
+                format!("{} is not a direct reference: {:?}", branch, target).into(),
+            )
+        })?;
+    checkout_commit(repo, oid, workdir)?;
+    Ok(oid)
+}
+
+fn checkout_commit(repo: &Repository, oid: ObjectId, workdir: &Path) -> Result<(), GitOpsError> {
     let tree_id = repo
         .find_object(oid)
-        .unwrap()
-        .into_commit()
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?
+        .try_into_commit()
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?
         .tree_id()
-        .unwrap();
-    let (mut state, _) = repo.index_from_tree(&tree_id).unwrap().into_parts();
-    let odb = repo.objects.clone().into_arc().unwrap();
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
+    let (mut state, _) = repo
+        .index_from_tree(&tree_id)
+        .map_err(|err| GitOpsError::CheckoutFailed(Box::new(err)))?
+        .into_parts();
+    let odb = repo
+        .objects
+        .clone()
+        .into_arc()
+        .map_err(|err| GitOpsError::CheckoutFailed(Box::new(err)))?;
     let db = make_finder(odb);
     let _outcome = gix::worktree::state::checkout(
         &mut state,
@@ -195,8 +223,18 @@ fn checkout_worktree(
         &AtomicBool::default(),
         gix::worktree::state::checkout::Options::default(),
     )
-    .unwrap();
-    Ok(oid)
+    .map_err(|err| GitOpsError::CheckoutFailed(Box::new(err)))?;
+    Ok(())
+}
+
+fn configure_repo(repo: &mut Repository) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // TODO Workaround for gitoxide not supporting empty user.email
+    let mut gitconfig = repo.config_snapshot_mut();
+    gitconfig.set_value(&User::NAME, "kitops")?;
+    gitconfig.set_value(&User::EMAIL, "none")?;
+    gitconfig.set_value(&Credentials::TERMINAL_PROMPT, "false")?;
+    gitconfig.commit()?;
+    Ok(())
 }
 
 pub fn ensure_worktree<P, Q>(
@@ -212,16 +250,13 @@ where
 {
     let repodir = repodir.as_ref();
     let workdir = workdir.as_ref();
-    let repo = if repodir.join(".git").try_exists().unwrap() {
+    let repo_exists = repodir
+        .join(".git")
+        .try_exists()
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
+    let repo = if repo_exists {
         let mut repo = gix::open(repodir).map_err(GitOpsError::OpenRepo)?;
-        // TODO Workaround for gitoxide not supporting empty user.email
-        let mut gitconfig = repo.config_snapshot_mut();
-        gitconfig.set_value(&User::NAME, "kitops").unwrap();
-        gitconfig.set_value(&User::EMAIL, "none").unwrap();
-        gitconfig
-            .set_value(&Credentials::TERMINAL_PROMPT, "false")
-            .unwrap();
-        gitconfig.commit().unwrap();
+        configure_repo(&mut repo).map_err(GitOpsError::CorruptRepo)?;
         fetch_repo(&repo, url, branch, deadline)?;
         repo
     } else {
@@ -230,11 +265,192 @@ where
     checkout_worktree(&repo, branch, workdir)
 }
 
+/// Check out a commit already present in the local clone, e.g. to roll back.
+pub fn checkout_sha<P, Q>(repodir: P, sha: ObjectId, workdir: Q) -> Result<(), GitOpsError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let repo = gix::open(repodir.as_ref()).map_err(GitOpsError::OpenRepo)?;
+    checkout_commit(&repo, sha, workdir.as_ref())
+}
+
+/// Files added, changed or removed between two commits in the local clone.
+pub fn changed_paths<P>(
+    repodir: P,
+    from: ObjectId,
+    to: ObjectId,
+) -> Result<Vec<String>, GitOpsError>
+where
+    P: AsRef<Path>,
+{
+    let repo = gix::open(repodir.as_ref()).map_err(GitOpsError::OpenRepo)?;
+    let before = tree_entries(&repo, from)?;
+    let after = tree_entries(&repo, to)?;
+    let mut changed = after
+        .iter()
+        .filter(|(path, oid)| before.get(*path) != Some(*oid))
+        .chain(before.iter().filter(|(path, _)| !after.contains_key(*path)))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    changed.sort();
+    Ok(changed)
+}
+
+/// Whether `descendant` is `ancestor` or has it in its history. History
+/// missing from the local clone counts as not containing `ancestor`.
+pub fn is_ancestor<P>(
+    repodir: P,
+    ancestor: ObjectId,
+    descendant: ObjectId,
+) -> Result<bool, GitOpsError>
+where
+    P: AsRef<Path>,
+{
+    let repo = gix::open(repodir.as_ref()).map_err(GitOpsError::OpenRepo)?;
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([descendant]);
+    while let Some(oid) = queue.pop_front() {
+        if oid == ancestor {
+            return Ok(true);
+        }
+        if !seen.insert(oid) {
+            continue;
+        }
+        // E.g. beyond a shallow boundary; the ancestor may only be missing on this path
+        let Ok(object) = repo.find_object(oid) else {
+            continue;
+        };
+        let commit = object
+            .try_into_commit()
+            .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
+        queue.extend(commit.parent_ids().map(|id| id.detach()));
+    }
+    Ok(false)
+}
+
+/// A commit's signature and the commit data it signs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitSignature {
+    pub signature: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+/// Signatures of the commits reachable from `to` but not from `from`, or only
+/// of `to` if `from` is not given.
+pub fn commit_signatures<P>(
+    repodir: P,
+    from: Option<ObjectId>,
+    to: ObjectId,
+) -> Result<Vec<(ObjectId, Option<CommitSignature>)>, GitOpsError>
+where
+    P: AsRef<Path>,
+{
+    let repo = gix::open(repodir.as_ref()).map_err(GitOpsError::OpenRepo)?;
+    // Merged branches lead back into history that was deployed along with `from`
+    let mut seen = match from {
+        Some(from) => ancestors(&repo, from)?,
+        None => HashSet::new(),
+    };
+    let mut signatures = Vec::new();
+    let mut queue = VecDeque::from([to]);
+    while let Some(oid) = queue.pop_front() {
+        if !seen.insert(oid) {
+            continue;
+        }
+        let commit = repo
+            .find_object(oid)
+            .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?
+            .try_into_commit()
+            .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
+        signatures.push((oid, split_signature(&commit.data)));
+        if from.is_some() {
+            queue.extend(commit.parent_ids().map(|id| id.detach()));
+        }
+    }
+    Ok(signatures)
+}
+
+/// `tip` and all commits in its history. History missing from the local
+/// clone is skipped.
+fn ancestors(repo: &Repository, tip: ObjectId) -> Result<HashSet<ObjectId>, GitOpsError> {
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::from([tip]);
+    while let Some(oid) = queue.pop_front() {
+        if !seen.insert(oid) {
+            continue;
+        }
+        let Ok(object) = repo.find_object(oid) else {
+            continue;
+        };
+        let commit = object
+            .try_into_commit()
+            .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
+        queue.extend(commit.parent_ids().map(|id| id.detach()));
+    }
+    Ok(seen)
+}
+
+/// Separate the gpgsig header from the rest of a raw commit, which is what
+/// the signature covers.
+fn split_signature(data: &[u8]) -> Option<CommitSignature> {
+    let mut signature: Option<Vec<u8>> = None;
+    let mut payload = Vec::with_capacity(data.len());
+    let mut in_signature = false;
+    let mut in_headers = true;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        if in_headers {
+            if in_signature && line.starts_with(b" ") {
+                signature.as_mut().unwrap().extend_from_slice(&line[1..]);
+                continue;
+            }
+            in_signature = false;
+            if let Some(value) = line
+                .strip_prefix(b"gpgsig ")
+                .or_else(|| line.strip_prefix(b"gpgsig-sha256 "))
+            {
+                signature = Some(value.to_vec());
+                in_signature = true;
+                continue;
+            }
+            in_headers = line != b"\n";
+        }
+        payload.extend_from_slice(line);
+    }
+    signature.map(|signature| CommitSignature { signature, payload })
+}
+
+fn tree_entries(
+    repo: &Repository,
+    oid: ObjectId,
+) -> Result<HashMap<String, ObjectId>, GitOpsError> {
+    let tree = repo
+        .find_object(oid)
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?
+        .try_into_commit()
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?
+        .tree()
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
+    let mut recorder = Recorder::default();
+    tree.traverse()
+        .breadthfirst(&mut recorder)
+        .map_err(|err| GitOpsError::CorruptRepo(Box::new(err)))?;
+    Ok(recorder
+        .records
+        .into_iter()
+        .filter(|entry| !entry.mode.is_tree())
+        .map(|entry| (entry.filepath.to_string(), entry.oid))
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::{Duration, Instant};
 
-    use crate::gix::{clone_repo, fetch_repo};
+    use crate::{
+        errors::GitOpsError,
+        gix::{checkout_worktree, clone_repo, fetch_repo, split_signature},
+    };
 
     const TEST_URL: &str = "https://example.com";
 
@@ -253,4 +469,39 @@ mod tests {
         let result = fetch_repo(&repo, TEST_URL.try_into().unwrap(), "main", deadline);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn checkout_missing_branch() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo = gix::init_bare(repo_dir.path()).unwrap();
+        let workdir = tempfile::tempdir().unwrap();
+        let result = checkout_worktree(&repo, "no-such-branch", workdir.path());
+        assert!(matches!(result, Err(GitOpsError::MissingBranch(_))));
+    }
+
+    #[test]
+    fn split_signature_from_commit() {
+        let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A <a@example.com> 0 +0000\n\
+committer A <a@example.com> 0 +0000\n\
+gpgsig -----BEGIN SSH SIGNATURE-----\n \
+ze-signature\n \
+-----END SSH SIGNATURE-----\n\
+\n\
+ze message\n";
+        let signed = split_signature(commit).unwrap();
+        assert_eq!(
+            signed.signature,
+            b"-----BEGIN SSH SIGNATURE-----\nze-signature\n-----END SSH SIGNATURE-----\n"
+        );
+        assert_eq!(
+            signed.payload,
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A <a@example.com> 0 +0000\n\
+committer A <a@example.com> 0 +0000\n\
+\n\
+ze message\n"
+        );
+        assert_eq!(split_signature(&signed.payload), None);
+    }
 }
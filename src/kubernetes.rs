@@ -0,0 +1,506 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{
+    blocking::{Client, ClientBuilder, RequestBuilder},
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    Certificate, StatusCode,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::GitOpsError,
+    state::State,
+    store::{apply_update, deserialize_state, serialize_state, Store},
+};
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Minimal client for namespaced Kubernetes objects, authenticating as the
+/// pod's service account.
+pub struct KubernetesClient {
+    base_url: String,
+    namespace: String,
+    token_file: PathBuf,
+    client: Client,
+}
+
+impl KubernetesClient {
+    /// Configure from the environment of a pod. Namespace defaults to that of the pod.
+    pub fn in_cluster(namespace: Option<String>) -> Result<Self, GitOpsError> {
+        let host = env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+            GitOpsError::KubernetesConfig("KUBERNETES_SERVICE_HOST is not set".to_owned())
+        })?;
+        let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+        let dir = Path::new(SERVICE_ACCOUNT_DIR);
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => read_service_account_file(&dir.join("namespace"))?,
+        };
+        let ca = std::fs::read(dir.join("ca.crt"))
+            .map_err(|err| GitOpsError::KubernetesConfig(format!("ca.crt: {}", err)))
+            .and_then(|pem| {
+                Certificate::from_pem(&pem)
+                    .map_err(|err| GitOpsError::KubernetesConfig(format!("ca.crt: {}", err)))
+            })?;
+        let client = ClientBuilder::new()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(30))
+            .add_root_certificate(ca)
+            .build()
+            .map_err(|err| GitOpsError::KubernetesConfig(format!("HTTP client: {}", err)))?;
+        Ok(Self {
+            base_url: format!("https://{}:{}", host, port),
+            namespace,
+            token_file: dir.join("token"),
+            client,
+        })
+    }
+
+    /// Talk to the API server at the given URL over plain HTTP, e.g. through kubectl proxy.
+    pub fn new(base_url: &str, namespace: &str, token_file: PathBuf) -> Result<Self, GitOpsError> {
+        let client = ClientBuilder::new()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|err| GitOpsError::KubernetesConfig(format!("HTTP client: {}", err)))?;
+        Ok(Self {
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            namespace: namespace.to_owned(),
+            token_file,
+            client,
+        })
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Returns None if the object does not exist.
+    pub fn get(&self, api: &str, kind: &str, name: &str) -> Result<Option<Value>, GitOpsError> {
+        let url = self.url(api, kind, Some(name));
+        self.send(self.client.get(&url), url)
+    }
+
+    /// Returns the created object, or None if it already exists.
+    pub fn create(
+        &self,
+        api: &str,
+        kind: &str,
+        object: &Value,
+    ) -> Result<Option<Value>, GitOpsError> {
+        let url = self.url(api, kind, None);
+        self.send(self.client.post(&url).json(object), url)
+    }
+
+    /// Returns the updated object, or None if it was changed since it was
+    /// read, i.e. its metadata.resourceVersion is no longer current, or deleted.
+    pub fn replace(
+        &self,
+        api: &str,
+        kind: &str,
+        name: &str,
+        object: &Value,
+    ) -> Result<Option<Value>, GitOpsError> {
+        let url = self.url(api, kind, Some(name));
+        self.send(self.client.put(&url).json(object), url)
+    }
+
+    fn url(&self, api: &str, kind: &str, name: Option<&str>) -> String {
+        let url = format!(
+            "{}/{}/namespaces/{}/{}",
+            self.base_url, api, self.namespace, kind
+        );
+        match name {
+            Some(name) => format!("{}/{}", url, name),
+            None => url,
+        }
+    }
+
+    /// Conflicts and missing objects are expected outcomes and yield None.
+    fn send(&self, request: RequestBuilder, url: String) -> Result<Option<Value>, GitOpsError> {
+        // The token file is rotated by kubelet, so read it for each request
+        let token = read_service_account_file(&self.token_file)?;
+        let res = request
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .send()
+            .map_err(GitOpsError::KubernetesNetworkError)?;
+        match res.status() {
+            StatusCode::NOT_FOUND | StatusCode::CONFLICT => Ok(None),
+            status if status.is_success() => res
+                .json()
+                .map(Some)
+                .map_err(GitOpsError::KubernetesNetworkError),
+            status => Err(GitOpsError::KubernetesApiError(
+                url,
+                status,
+                res.text()
+                    .unwrap_or("Kubernetes API returned unparseable error".to_owned()),
+            )),
+        }
+    }
+}
+
+fn read_service_account_file(path: &Path) -> Result<String, GitOpsError> {
+    std::fs::read_to_string(path)
+        .map(|content| content.trim().to_owned())
+        .map_err(|err| GitOpsError::KubernetesConfig(format!("{}: {}", path.display(), err)))
+}
+
+/// Object names must be lowercase alphanumerics, '-' and '.'. A hash of the
+/// raw id keeps ids that sanitize alike, e.g. `a_b` and `A.b`, apart.
+pub fn object_name(prefix: &str, id: &str) -> String {
+    let name = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>();
+    let hash = Sha256::digest(id.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let mut name = format!("{}-{}", prefix, name.trim_matches(|c| c == '-' || c == '.'));
+    name.truncate(253 - hash.len() - 1);
+    format!(
+        "{}-{}",
+        name.trim_end_matches(|c| c == '-' || c == '.'),
+        hash
+    )
+}
+
+const CORE_API: &str = "api/v1";
+/// Key in the object's data that holds the state document
+const STATE_KEY: &str = "state.yaml";
+/// Give up persisting after this many consecutive conflicting writes.
+const MAX_CONFLICTS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateObject {
+    ConfigMap,
+    /// Data is base64-encoded, per the API
+    Secret,
+}
+
+impl StateObject {
+    fn kind(self) -> &'static str {
+        match self {
+            Self::ConfigMap => "ConfigMap",
+            Self::Secret => "Secret",
+        }
+    }
+
+    fn resource(self) -> &'static str {
+        match self {
+            Self::ConfigMap => "configmaps",
+            Self::Secret => "secrets",
+        }
+    }
+
+    fn encode(self, buf: Vec<u8>) -> String {
+        match self {
+            Self::ConfigMap => String::from_utf8(buf).expect("YAML is UTF-8"),
+            Self::Secret => STANDARD.encode(buf),
+        }
+    }
+
+    fn decode(self, data: &str) -> Result<Vec<u8>, GitOpsError> {
+        match self {
+            Self::ConfigMap => Ok(data.as_bytes().to_vec()),
+            Self::Secret => STANDARD.decode(data).map_err(|err| {
+                GitOpsError::KubernetesConfig(format!("malformed secret data: {}", err))
+            }),
+        }
+    }
+}
+
+/// State document kept in a ConfigMap or Secret. Writes carry the
+/// resourceVersion last read, and are merged and retried on conflict.
+pub struct KubernetesStore {
+    client: KubernetesClient,
+    kind: StateObject,
+    name: String,
+    object: Option<Value>,
+    state: HashMap<String, State>,
+}
+
+impl KubernetesStore {
+    pub fn load(
+        client: KubernetesClient,
+        kind: StateObject,
+        name: &str,
+    ) -> Result<Self, GitOpsError> {
+        let mut store = Self {
+            client,
+            kind,
+            name: name.to_owned(),
+            object: None,
+            state: HashMap::new(),
+        };
+        store.fetch()?;
+        Ok(store)
+    }
+
+    /// Parse kubernetes://[<namespace>]/(configmap|secret)/<name>; namespace
+    /// defaults to that of the pod.
+    pub fn from_url(url: &str) -> Result<Self, GitOpsError> {
+        let invalid = || GitOpsError::InvalidStateUrl(url.to_owned());
+        let mut parts = url
+            .strip_prefix("kubernetes://")
+            .ok_or_else(invalid)?
+            .split('/');
+        let (Some(namespace), Some(kind), Some(name), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let kind = match kind {
+            "configmap" => StateObject::ConfigMap,
+            "secret" => StateObject::Secret,
+            _ => return Err(invalid()),
+        };
+        if name.is_empty() {
+            return Err(invalid());
+        }
+        let namespace = Some(namespace.to_owned()).filter(|ns| !ns.is_empty());
+        Self::load(KubernetesClient::in_cluster(namespace)?, kind, name)
+    }
+
+    fn fetch(&mut self) -> Result<(), GitOpsError> {
+        self.object = self
+            .client
+            .get(CORE_API, self.kind.resource(), &self.name)?;
+        self.state = match self
+            .object
+            .as_ref()
+            .and_then(|o| o["data"][STATE_KEY].as_str())
+        {
+            Some(data) => deserialize_state(&self.kind.decode(data)?)?,
+            None => HashMap::new(),
+        };
+        Ok(())
+    }
+
+    /// Write our change, merging in changes from other instances on conflict.
+    fn update(&mut self, id: String, state: Option<&State>) -> Result<(), GitOpsError> {
+        apply_update(&mut self.state, &id, state);
+        for _ in 0..MAX_CONFLICTS {
+            if self.write()? {
+                return Ok(());
+            }
+            // Another instance got there first; keep its changes and reapply ours
+            self.fetch()?;
+            apply_update(&mut self.state, &id, state);
+        }
+        Err(GitOpsError::StateConflict(id))
+    }
+
+    /// Returns false if someone else changed the object since we read it.
+    fn write(&mut self) -> Result<bool, GitOpsError> {
+        let data = self.kind.encode(serialize_state(&self.state)?);
+        let written = match self.object.take() {
+            Some(mut object) => {
+                object["data"][STATE_KEY] = Value::String(data);
+                self.client
+                    .replace(CORE_API, self.kind.resource(), &self.name, &object)?
+            }
+            None => {
+                let object = json!({
+                    "apiVersion": "v1",
+                    "kind": self.kind.kind(),
+                    "metadata": { "name": self.name, "namespace": self.client.namespace() },
+                    "data": { STATE_KEY: data },
+                });
+                self.client
+                    .create(CORE_API, self.kind.resource(), &object)?
+            }
+        };
+        self.object = written;
+        Ok(self.object.is_some())
+    }
+}
+
+impl Store for KubernetesStore {
+    fn get(&self, id: &str) -> Option<&State> {
+        self.state.get(id)
+    }
+
+    fn ids(&self) -> Vec<String> {
+        self.state.keys().cloned().collect()
+    }
+
+    fn retain(&mut self, task_ids: HashSet<String>) {
+        self.state.retain(|id, _| task_ids.contains(id));
+    }
+
+    fn persist(&mut self, id: String, state: &State) -> Result<(), GitOpsError> {
+        self.update(id, Some(state))
+    }
+
+    fn remove(&mut self, id: &str) -> Result<(), GitOpsError> {
+        self.update(id.to_owned(), None)
+    }
+
+    fn reload(&mut self, id: &str) -> Result<Option<State>, GitOpsError> {
+        self.fetch()?;
+        Ok(self.state.get(id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use gix::{hash::Kind, ObjectId};
+    use serde_json::Value;
+
+    use crate::{
+        state::State,
+        store::Store,
+        testutils::{fake_server, FakeResponse},
+    };
+
+    use super::{KubernetesClient, KubernetesStore, StateObject};
+
+    /// Stand-in for an API server that stores objects by path and rejects
+    /// writes with a stale resourceVersion.
+    fn fake_api_server() -> (String, Arc<Mutex<Option<Value>>>) {
+        let stored = Arc::new(Mutex::new(None::<Value>));
+        let handle = stored.clone();
+        let mut version = 0;
+        let endpoint = fake_server(move |req| {
+            assert_eq!(req.headers["authorization"], "Bearer ze-token");
+            let mut stored = handle.lock().unwrap();
+            let current = stored
+                .as_ref()
+                .map(|o| o["metadata"]["resourceVersion"].clone());
+            let response = |object: &Value| {
+                FakeResponse::new("200 OK").with_body(serde_json::to_vec(object).unwrap())
+            };
+            match req.method.as_str() {
+                "GET" => match stored.as_ref() {
+                    Some(object) => response(object),
+                    None => FakeResponse::new("404 Not Found"),
+                },
+                "POST" if stored.is_some() => FakeResponse::new("409 Conflict"),
+                "PUT" if stored.is_none() => FakeResponse::new("404 Not Found"),
+                "PUT" if current.as_ref() != Some(&req_version(&req.body)) => {
+                    FakeResponse::new("409 Conflict")
+                }
+                _ => {
+                    let mut object: Value = serde_json::from_slice(&req.body).unwrap();
+                    version += 1;
+                    object["metadata"]["resourceVersion"] = Value::String(version.to_string());
+                    let res = response(&object);
+                    *stored = Some(object);
+                    res
+                }
+            }
+        });
+        (endpoint, stored)
+    }
+
+    fn req_version(body: &[u8]) -> Value {
+        let object: Value = serde_json::from_slice(body).unwrap();
+        object["metadata"]["resourceVersion"].clone()
+    }
+
+    fn store(
+        endpoint: &str,
+        kind: StateObject,
+        token: &tempfile::NamedTempFile,
+    ) -> KubernetesStore {
+        let client =
+            KubernetesClient::new(endpoint, "ze-namespace", token.path().to_path_buf()).unwrap();
+        KubernetesStore::load(client, kind, "kitops-state").unwrap()
+    }
+
+    fn token() -> tempfile::NamedTempFile {
+        let token = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(token.path(), "ze-token\n").unwrap();
+        token
+    }
+
+    fn state() -> State {
+        State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn configmap_store_roundtrip() {
+        let (endpoint, stored) = fake_api_server();
+        let token = token();
+        let mut first = store(&endpoint, StateObject::ConfigMap, &token);
+        assert!(first.get("ze-task").is_none());
+        first.persist("ze-task".to_owned(), &state()).unwrap();
+        first.persist("ze-task".to_owned(), &state()).unwrap();
+        let object = stored.lock().unwrap().clone().unwrap();
+        assert_eq!(object["kind"], "ConfigMap");
+        assert!(object["data"]["state.yaml"]
+            .as_str()
+            .unwrap()
+            .contains("ze-task"));
+        let second = store(&endpoint, StateObject::ConfigMap, &token);
+        assert!(second.get("ze-task").unwrap().current_sha.is_empty_blob());
+    }
+
+    #[test]
+    fn secret_store_encodes_data() {
+        let (endpoint, stored) = fake_api_server();
+        let token = token();
+        let mut first = store(&endpoint, StateObject::Secret, &token);
+        first.persist("ze-task".to_owned(), &state()).unwrap();
+        let object = stored.lock().unwrap().clone().unwrap();
+        assert!(!object["data"]["state.yaml"]
+            .as_str()
+            .unwrap()
+            .contains("ze-task"));
+        let second = store(&endpoint, StateObject::Secret, &token);
+        assert!(second.get("ze-task").is_some());
+    }
+
+    #[test]
+    fn store_merges_concurrent_updates() {
+        let (endpoint, _) = fake_api_server();
+        let token = token();
+        let mut first = store(&endpoint, StateObject::ConfigMap, &token);
+        let mut second = store(&endpoint, StateObject::ConfigMap, &token);
+        first.persist("first".to_owned(), &state()).unwrap();
+        second.persist("second".to_owned(), &state()).unwrap();
+        first.persist("first".to_owned(), &state()).unwrap();
+        let third = store(&endpoint, StateObject::ConfigMap, &token);
+        assert!(third.get("first").is_some());
+        assert!(third.get("second").is_some());
+    }
+
+    #[test]
+    fn object_name_is_sanitized() {
+        assert_eq!(
+            super::object_name("kitops", "My Task/prod"),
+            "kitops-my-task-prod-a109b12c"
+        );
+    }
+
+    #[test]
+    fn object_names_keep_ids_apart() {
+        let names = ["a_b", "a.b", "a-b", "App", "app"]
+            .map(|id| super::object_name("kitops", id))
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(names.len(), 5);
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    errors::GitOpsError,
+    kubernetes::{object_name, KubernetesClient},
+};
+
+/// Ensures that a task is run by at most one kitops instance at a time.
+pub trait Lease: Send + Sync {
+    /// Take the lease on a task, or extend it if we already hold it. Returns
+    /// false if another instance holds a lease that has not yet expired.
+    fn acquire(&self, id: &str) -> Result<bool, GitOpsError>;
+    /// Let other instances take the task without waiting for expiry.
+    fn release(&self, id: &str) -> Result<(), GitOpsError>;
+    /// How long a lease is valid unless renewed.
+    fn duration(&self) -> Duration;
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LeaseRecord {
+    holder: String,
+    expires: SystemTime,
+}
+
+/// Lease records as files in a directory shared by all instances, e.g. a
+/// network volume. Updates are serialized with flock.
+pub struct FileLease {
+    dir: PathBuf,
+    holder: String,
+    duration: Duration,
+}
+
+impl FileLease {
+    pub fn new(dir: &Path, holder: String, duration: Duration) -> Result<Self, GitOpsError> {
+        std::fs::create_dir_all(dir).map_err(GitOpsError::LeaseFile)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            holder,
+            duration,
+        })
+    }
+
+    /// The file stays locked until it is dropped.
+    fn open_locked(&self, id: &str) -> Result<(File, Option<LeaseRecord>), GitOpsError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(
+                self.dir
+                    .join(format!("{}.lease", object_name("kitops", id))),
+            )
+            .map_err(GitOpsError::LeaseFile)?;
+        lock_exclusive(&file).map_err(GitOpsError::LeaseFile)?;
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)
+            .map_err(GitOpsError::LeaseFile)?;
+        // An empty or unreadable record means nobody holds the lease
+        Ok((file, serde_yaml::from_str(&buf).ok()))
+    }
+
+    fn write(file: &mut File, record: Option<&LeaseRecord>) -> Result<(), GitOpsError> {
+        let buf = match record {
+            Some(record) => serde_yaml::to_string(record).map_err(|err| {
+                GitOpsError::LeaseFile(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+            })?,
+            None => String::new(),
+        };
+        file.set_len(0).map_err(GitOpsError::LeaseFile)?;
+        file.rewind().map_err(GitOpsError::LeaseFile)?;
+        file.write_all(buf.as_bytes())
+            .map_err(GitOpsError::LeaseFile)?;
+        file.sync_all().map_err(GitOpsError::LeaseFile)
+    }
+}
+
+impl Lease for FileLease {
+    fn acquire(&self, id: &str) -> Result<bool, GitOpsError> {
+        let (mut file, current) = self.open_locked(id)?;
+        let now = SystemTime::now();
+        if current.is_some_and(|r| r.holder != self.holder && r.expires > now) {
+            return Ok(false);
+        }
+        let record = LeaseRecord {
+            holder: self.holder.clone(),
+            expires: now + self.duration,
+        };
+        Self::write(&mut file, Some(&record))?;
+        Ok(true)
+    }
+
+    fn release(&self, id: &str) -> Result<(), GitOpsError> {
+        let (mut file, current) = self.open_locked(id)?;
+        if current.is_some_and(|r| r.holder == self.holder) {
+            Self::write(&mut file, None)?;
+        }
+        Ok(())
+    }
+
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// load_lease refuses file leases here; failing beats racing other instances.
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "file locking requires flock",
+    ))
+}
+
+const LEASE_API: &str = "apis/coordination.k8s.io/v1";
+
+/// Leases as coordination.k8s.io Lease objects in the pod's namespace.
+/// Concurrent updates are caught by the API server through resourceVersion.
+pub struct KubernetesLease {
+    client: KubernetesClient,
+    holder: String,
+    duration: Duration,
+}
+
+impl KubernetesLease {
+    pub fn new(client: KubernetesClient, holder: String, duration: Duration) -> Self {
+        Self {
+            client,
+            holder,
+            duration,
+        }
+    }
+
+    fn spec(&self, acquired: &Value, now: &str) -> Value {
+        json!({
+            "holderIdentity": self.holder,
+            "leaseDurationSeconds": self.duration.as_secs().max(1),
+            "acquireTime": acquired,
+            "renewTime": now,
+        })
+    }
+}
+
+impl Lease for KubernetesLease {
+    fn acquire(&self, id: &str) -> Result<bool, GitOpsError> {
+        let name = object_name("kitops", id);
+        let now = Utc::now();
+        let now_str = Value::String(now.to_rfc3339_opts(SecondsFormat::Micros, true));
+        let Some(mut lease) = self.client.get(LEASE_API, "leases", &name)? else {
+            let lease = json!({
+                "apiVersion": "coordination.k8s.io/v1",
+                "kind": "Lease",
+                "metadata": { "name": name, "namespace": self.client.namespace() },
+                "spec": self.spec(&now_str, now_str.as_str().unwrap()),
+            });
+            return Ok(self.client.create(LEASE_API, "leases", &lease)?.is_some());
+        };
+        let spec = &lease["spec"];
+        let holder = spec["holderIdentity"].as_str();
+        let expires = spec["renewTime"]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .zip(spec["leaseDurationSeconds"].as_i64())
+            .map(|(renewed, secs)| renewed + chrono::Duration::seconds(secs));
+        let ours = holder == Some(self.holder.as_str());
+        if holder.is_some() && !ours && expires.is_some_and(|t| t > now) {
+            return Ok(false);
+        }
+        let acquired = if ours {
+            spec["acquireTime"].clone()
+        } else {
+            now_str.clone()
+        };
+        lease["spec"] = self.spec(&acquired, now_str.as_str().unwrap());
+        // Keeping metadata.resourceVersion makes this fail if someone else got there first
+        Ok(self
+            .client
+            .replace(LEASE_API, "leases", &name, &lease)?
+            .is_some())
+    }
+
+    fn release(&self, id: &str) -> Result<(), GitOpsError> {
+        let name = object_name("kitops", id);
+        if let Some(mut lease) = self.client.get(LEASE_API, "leases", &name)? {
+            if lease["spec"]["holderIdentity"].as_str() == Some(self.holder.as_str()) {
+                lease["spec"]["holderIdentity"] = Value::Null;
+                self.client.replace(LEASE_API, "leases", &name, &lease)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+// File leases need flock
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use super::{FileLease, Lease};
+
+    fn lease(dir: &tempfile::TempDir, holder: &str, duration: Duration) -> FileLease {
+        FileLease::new(dir.path(), holder.to_owned(), duration).unwrap()
+    }
+
+    #[test]
+    fn file_lease_excludes_other_holders() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = lease(&dir, "first", Duration::from_secs(60));
+        let second = lease(&dir, "second", Duration::from_secs(60));
+        assert!(first.acquire("ze-task").unwrap());
+        assert!(!second.acquire("ze-task").unwrap());
+        assert!(second.acquire("other-task").unwrap());
+        assert!(first.acquire("ze-task").unwrap());
+    }
+
+    #[test]
+    fn file_lease_can_be_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = lease(&dir, "first", Duration::from_secs(60));
+        let second = lease(&dir, "second", Duration::from_secs(60));
+        assert!(first.acquire("ze-task").unwrap());
+        second.release("ze-task").unwrap();
+        assert!(!second.acquire("ze-task").unwrap());
+        first.release("ze-task").unwrap();
+        assert!(second.acquire("ze-task").unwrap());
+    }
+
+    #[test]
+    fn file_lease_taken_over_after_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = lease(&dir, "first", Duration::from_millis(10));
+        let second = lease(&dir, "second", Duration::from_secs(60));
+        assert!(first.acquire("ze-task").unwrap());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(second.acquire("ze-task").unwrap());
+        assert!(!first.acquire("ze-task").unwrap());
+    }
+}
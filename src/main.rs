@@ -1,19 +1,42 @@
 #![allow(clippy::module_name_repetitions)]
 
 use clap::Parser;
+use kitops::admin::run_state_command;
 use kitops::errors::GitOpsError;
-use kitops::opts::{load_store, load_tasks, CliOptions};
-use kitops::run_tasks;
-use kitops::store::Store;
+use kitops::opts::{
+    configured_task_ids, load_lease, load_store, load_tasks, shutdown_on_signal, CliOptions,
+    Command,
+};
+use kitops::receiver::WorkloadEvent;
+use kitops::scheduler::{Scheduler, StorePersist};
 use kitops::task::ScheduledTask;
-use kitops::workload::GitWorkload;
 use std::collections::HashSet;
-use std::time::Duration;
+use std::process::ExitCode;
 
-fn main() -> Result<(), GitOpsError> {
+fn run() -> Result<(), GitOpsError> {
     let mut opts = CliOptions::parse();
+    if let Some(Command::State { ref command }) = opts.command {
+        let mut store = load_store(&opts)?;
+        if let Some(reason) = store.recovery() {
+            eprintln!("Warning: {}", reason);
+        }
+        let output = run_state_command(command, store.as_mut(), || configured_task_ids(&opts))?;
+        print!("{}", output);
+        return Ok(());
+    }
     opts.complete()?;
-    let mut tasks = load_tasks(&opts)?;
+    let mut scheduler = Scheduler::new(
+        opts.max_concurrent_runs,
+        opts.once_only,
+        opts.shutdown_grace_period,
+    );
+    if let Some(lease) = load_lease(&opts)? {
+        scheduler = scheduler.with_lease(lease);
+    }
+    let shutdown = shutdown_on_signal(scheduler.waker(), || {
+        eprintln!("Shutting down, waiting for running tasks to finish");
+    })?;
+    let mut tasks = load_tasks(&opts, &shutdown)?;
     let mut store = load_store(&opts)?;
     let task_ids = tasks.iter().map(ScheduledTask::id).collect::<HashSet<_>>();
     store.retain(task_ids);
@@ -21,11 +44,19 @@ fn main() -> Result<(), GitOpsError> {
         if let Some(s) = store.get(&task.id()) {
             task.set_state(s.clone());
         }
+        if let Some(reason) = store.recovery() {
+            task.notify(WorkloadEvent::StateRecovered(task.id(), reason.to_owned()));
+        }
+    }
+    scheduler.run(&mut tasks[..], StorePersist(store.as_mut()), &shutdown)
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(err.exit_code())
+        }
     }
-    run_tasks(
-        &mut tasks[..],
-        |t: &ScheduledTask<GitWorkload>| store.persist(t.id(), t),
-        opts.once_only,
-        Duration::from_secs(1),
-    )
 }
@@ -1,13 +1,29 @@
-use std::{fs::File, path::PathBuf, sync::mpsc::channel, thread::spawn, time::Duration};
+use std::{
+    collections::HashSet,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc,
+    },
+    thread::spawn,
+    time::Duration,
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use crate::{
+    azure::{AzureBlob, AzureBlobStore},
     config::{read_config, GitTaskConfig},
     errors::GitOpsError,
     github::{github_watcher, GithubUrlProvider},
     gix::DefaultUrlProvider,
+    kubernetes::{KubernetesClient, KubernetesStore},
+    lease::{FileLease, KubernetesLease, Lease},
     receiver::logging_receiver,
+    s3::{S3Object, S3Store},
+    scheduler::Wakeup,
     store::{FileStore, Store},
     task::ScheduledTask,
     workload::GitWorkload,
@@ -17,9 +33,15 @@ const DEFAULT_BRANCH: &str = "main";
 
 #[derive(Parser)]
 pub struct CliOptions {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// Path where state is stored
     #[clap(long, default_value = "./state.yaml")]
     pub state_file: PathBuf,
+    /// Where to store state instead of --state-file: file://<path>, s3://<bucket>/<key>,
+    /// azblob://<account>/<container>/<blob> or kubernetes://[<namespace>]/(configmap|secret)/<name>
+    #[clap(long)]
+    pub state_url: Option<String>,
     /// YAML format task descriptions
     #[clap(long)]
     pub config_file: Option<String>,
@@ -38,6 +60,9 @@ pub struct CliOptions {
     /// Environment variable for action
     #[clap(long)]
     pub environment: Vec<String>,
+    /// Variable in kitops' environment that actions may receive through secret_env
+    #[clap(long)]
+    pub secret_env_allow: Vec<String>,
     /// GitHub App ID for authentication with private repos and commit status updates
     #[clap(long)]
     pub github_app_id: Option<String>,
@@ -56,6 +81,46 @@ pub struct CliOptions {
     /// Run once and exit
     #[clap(long)]
     pub once_only: bool,
+    /// Max number of tasks running at the same time
+    #[clap(long, default_value_t = 4)]
+    pub max_concurrent_runs: usize,
+    /// Max time to wait for running tasks on SIGTERM/SIGINT (e.g. 1m, 30s)
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    pub shutdown_grace_period: Duration,
+    /// Share tasks with other instances through leases (file:///<dir>, Unix only, or
+    /// kubernetes://[<namespace>])
+    #[clap(long)]
+    pub lease_url: Option<String>,
+    /// Identifies this instance in leases (default: $HOSTNAME and pid)
+    #[clap(long)]
+    pub lease_holder: Option<String>,
+    /// How long a lease lasts unless renewed; other instances take over after this
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "60s")]
+    pub lease_duration: Duration,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Inspect or edit task state. Stop all kitops instances sharing the state first, or
+    /// they may overwrite your changes
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StateCommand {
+    /// List tasks with their current SHA, next run and last result
+    List,
+    /// Show all recorded state for a task
+    Show { task: String },
+    /// Forget the deployed SHA so that actions run again on the next check
+    Reset { task: String },
+    /// Record a commit as deployed, e.g. after fixing a failed deploy by hand
+    SetSha { task: String, sha: String },
+    /// Remove state for tasks no longer in --config-file (or --url)
+    Prune,
 }
 
 impl CliOptions {
@@ -89,7 +154,11 @@ impl CliOptions {
     }
 }
 
-fn into_task(mut config: GitTaskConfig, opts: &CliOptions) -> ScheduledTask<GitWorkload> {
+fn into_task(
+    mut config: GitTaskConfig,
+    opts: &CliOptions,
+    shutdown: &Arc<AtomicBool>,
+) -> ScheduledTask<GitWorkload> {
     let repo_dir = opts.repo_dir.clone().unwrap();
     let github = config.github.take();
     let mut work = if let Some(github) = github {
@@ -104,44 +173,128 @@ fn into_task(mut config: GitTaskConfig, opts: &CliOptions) -> ScheduledTask<GitW
         let provider = DefaultUrlProvider::new(config.git.url.clone());
         GitWorkload::new(config, provider, &repo_dir)
     };
+    work.set_shutdown(Arc::clone(shutdown));
+    work.allow_secret_env(opts.secret_env_allow.iter().cloned().collect());
     let (tx, rx) = channel();
     work.watch(move |event| {
         tx.send(event)
             .map_err(|e| GitOpsError::NotifyError(format!("{}", e)))
     });
-    // TODO Handle TERM
     spawn(move || {
         logging_receiver(&rx);
     });
     ScheduledTask::new(work)
 }
 
-fn tasks_from_file(opts: &CliOptions) -> Result<Vec<ScheduledTask<GitWorkload>>, GitOpsError> {
+fn tasks_from_file(
+    opts: &CliOptions,
+    shutdown: &Arc<AtomicBool>,
+) -> Result<Vec<ScheduledTask<GitWorkload>>, GitOpsError> {
     let config =
         File::open(opts.config_file.clone().unwrap()).map_err(GitOpsError::MissingConfig)?;
     let config_file = read_config(config)?;
     Ok(config_file
         .tasks
         .into_iter()
-        .map(|c| into_task(c, opts))
+        .map(|c| into_task(c, opts, shutdown))
         .collect())
 }
 
-fn tasks_from_opts(opts: &CliOptions) -> Result<Vec<ScheduledTask<GitWorkload>>, GitOpsError> {
+fn tasks_from_opts(
+    opts: &CliOptions,
+    shutdown: &Arc<AtomicBool>,
+) -> Result<Vec<ScheduledTask<GitWorkload>>, GitOpsError> {
     let config: GitTaskConfig = TryFrom::try_from(opts)?;
-    Ok(vec![into_task(config, opts)])
+    Ok(vec![into_task(config, opts, shutdown)])
 }
 
-pub fn load_tasks(opts: &CliOptions) -> Result<Vec<ScheduledTask<GitWorkload>>, GitOpsError> {
+pub fn load_tasks(
+    opts: &CliOptions,
+    shutdown: &Arc<AtomicBool>,
+) -> Result<Vec<ScheduledTask<GitWorkload>>, GitOpsError> {
     if opts.url.is_some() {
-        tasks_from_opts(opts)
+        tasks_from_opts(opts, shutdown)
+    } else {
+        tasks_from_file(opts, shutdown)
+    }
+}
+
+/// Ids of the configured tasks, without setting them up.
+pub fn configured_task_ids(opts: &CliOptions) -> Result<HashSet<String>, GitOpsError> {
+    if opts.url.is_some() && opts.action.is_some() {
+        let config: GitTaskConfig = TryFrom::try_from(opts)?;
+        Ok(HashSet::from([config.name]))
+    } else if let Some(ref path) = opts.config_file {
+        let config = File::open(path).map_err(GitOpsError::MissingConfig)?;
+        let config_file = read_config(config)?;
+        Ok(config_file.tasks.into_iter().map(|c| c.name).collect())
+    } else {
+        Err(GitOpsError::ConfigMethodConflict)
+    }
+}
+
+/// Raise the returned flag and wake the scheduler on SIGTERM/SIGINT (Ctrl-C on Windows).
+/// `on_shutdown` is called on the first signal.
+pub fn shutdown_on_signal(
+    waker: Sender<Wakeup>,
+    on_shutdown: impl Fn() + Send + 'static,
+) -> Result<Arc<AtomicBool>, GitOpsError> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&shutdown);
+    ctrlc::set_handler(move || {
+        if !flag.swap(true, Ordering::Relaxed) {
+            on_shutdown();
+        }
+        let _ = waker.send(Wakeup::External);
+    })
+    .map_err(GitOpsError::SignalHandler)?;
+    Ok(shutdown)
+}
+
+pub fn load_lease(opts: &CliOptions) -> Result<Option<Box<dyn Lease>>, GitOpsError> {
+    let Some(url) = &opts.lease_url else {
+        return Ok(None);
+    };
+    let holder = opts.lease_holder.clone().unwrap_or_else(|| {
+        let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "kitops".to_owned());
+        format!("{}-{}", host, std::process::id())
+    });
+    if let Some(dir) = url.strip_prefix("file://") {
+        if cfg!(not(unix)) {
+            return Err(GitOpsError::UnsupportedLease(url.clone()));
+        }
+        let lease = FileLease::new(Path::new(dir), holder, opts.lease_duration)?;
+        Ok(Some(Box::new(lease)))
+    } else if let Some(namespace) = url.strip_prefix("kubernetes://") {
+        let namespace = Some(namespace.to_owned()).filter(|ns| !ns.is_empty());
+        let client = KubernetesClient::in_cluster(namespace)?;
+        Ok(Some(Box::new(KubernetesLease::new(
+            client,
+            holder,
+            opts.lease_duration,
+        ))))
     } else {
-        tasks_from_file(opts)
+        Err(GitOpsError::InvalidLeaseUrl(url.clone()))
     }
 }
 
-pub fn load_store(opts: &CliOptions) -> Result<impl Store, GitOpsError> {
-    FileStore::from_file(&opts.state_file)
+pub fn load_store(opts: &CliOptions) -> Result<Box<dyn Store>, GitOpsError> {
+    match &opts.state_url {
+        None => Ok(Box::new(FileStore::from_file(&opts.state_file)?)),
+        Some(url) if url.starts_with("s3://") => {
+            Ok(Box::new(S3Store::load(S3Object::from_url(url)?)?))
+        }
+        Some(url) if url.starts_with("azblob://") => {
+            Ok(Box::new(AzureBlobStore::load(AzureBlob::from_url(url)?)?))
+        }
+        Some(url) if url.starts_with("kubernetes://") => {
+            Ok(Box::new(KubernetesStore::from_url(url)?))
+        }
+        Some(url) => match url.strip_prefix("file://") {
+            Some(path) => Ok(Box::new(FileStore::from_file(Path::new(path))?)),
+            None => Err(GitOpsError::InvalidStateUrl(url.clone())),
+        },
+    }
 }
 
 #[test]
@@ -170,3 +323,28 @@ fn complete_cli_options_conflicting_args() {
     let res = opts.complete();
     assert!(matches!(res, Err(GitOpsError::ConfigMethodConflict)));
 }
+
+#[test]
+fn load_lease_rejects_unknown_scheme() {
+    let opts = CliOptions::parse_from(&["kitops", "--lease-url", "s3://bucket"]);
+    let res = load_lease(&opts);
+    assert!(matches!(res, Err(GitOpsError::InvalidLeaseUrl(_))));
+}
+
+#[test]
+fn load_store_rejects_unknown_scheme() {
+    let opts = CliOptions::parse_from(&["kitops", "--state-url", "ftp://host/state.yaml"]);
+    let res = load_store(&opts);
+    assert!(matches!(res, Err(GitOpsError::InvalidStateUrl(_))));
+}
+
+#[test]
+fn parse_state_subcommand() {
+    let opts = CliOptions::parse_from(&["kitops", "state", "set-sha", "ze-task", "abc123"]);
+    assert!(matches!(
+        opts.command,
+        Some(Command::State {
+            command: StateCommand::SetSha { ref task, ref sha }
+        }) if task == "ze-task" && sha == "abc123"
+    ));
+}
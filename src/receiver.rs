@@ -2,22 +2,69 @@ use std::{process::ExitStatus, sync::mpsc::Receiver};
 
 use gix::{hash::Kind, ObjectId};
 
+use crate::actions::ActionResult;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SourceType {
     StdOut,
     StdErr,
 }
 
+/// How an action that ran past its deadline ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Termination {
+    /// Exited within the grace period after SIGTERM
+    Graceful(ExitStatus),
+    /// Still running when the grace period ended and killed
+    Killed,
+}
+
+/// What kitops was doing with a task's lease when it failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeaseOperation {
+    Acquire,
+    Renew,
+    Release,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum WorkloadEvent {
     // TODO Name types would be nice
     Changes(String, ObjectId, ObjectId),
+    FetchFailed(String, String),
+    Deferred(String, ObjectId),
+    AwaitingDependencies(String, ObjectId),
+    /// Task, refused SHA and why its signature did not verify
+    Unverified(String, ObjectId, String),
+    /// Task, deployed SHA, new SHA that does not descend from it and whether
+    /// the new SHA was refused
+    NonFastForward(String, ObjectId, ObjectId, bool),
     ActionOutput(String, SourceType, Vec<u8>),
     ActionExit(String, ExitStatus),
+    /// Outcome of an action after the given number of attempts
+    ActionFinished(String, ActionResult, u32),
     Success(String, ObjectId),
     Failure(String, String, ObjectId),
     Error(String, String, ObjectId),
-    Timeout(String),
+    /// Task, failed SHA and the last successful SHA being restored
+    RollingBack(String, ObjectId, ObjectId),
+    /// As RollingBack, plus why the rollback failed, if it did
+    RolledBack(String, ObjectId, ObjectId, Option<String>),
+    /// Task and why one of its hooks failed
+    HookFailed(String, String),
+    Timeout(String, Termination),
+    /// Action, its container and why the container could not be killed
+    ContainerKillFailed(String, String, String),
+    /// Task, what it was doing with its lease and why that failed
+    LeaseFailed(String, LeaseOperation, String),
+    /// Task whose lease another instance has taken over
+    LeaseLost(String),
+    /// Task and why its state could not be reloaded after taking its lease
+    ReloadFailed(String, String),
+    /// Task and why its state could not be persisted, even after retrying
+    PersistFailed(String, String),
+    /// Task and why its state was restored from a backup
+    StateRecovered(String, String),
 }
 
 pub fn logging_receiver(events: &Receiver<WorkloadEvent>) {
@@ -30,6 +77,31 @@ pub fn logging_receiver(events: &Receiver<WorkloadEvent>) {
                     println!("{}: Updated repo {} -> {}", name, prev_sha, new_sha);
                 }
             }
+            WorkloadEvent::FetchFailed(name, error) => {
+                println!("{}: failed to fetch repo: {}", name, error)
+            }
+            WorkloadEvent::Deferred(name, new_sha) => {
+                println!("{}: {} waiting for deploy window", name, new_sha)
+            }
+            WorkloadEvent::AwaitingDependencies(name, new_sha) => {
+                println!("{}: {} waiting for dependencies", name, new_sha)
+            }
+            WorkloadEvent::Unverified(name, sha, reason) => {
+                println!("{}: refusing unverified commit {}: {}", name, sha, reason)
+            }
+            WorkloadEvent::NonFastForward(name, prev_sha, new_sha, refused) => {
+                if refused {
+                    println!(
+                        "{}: refusing {}, which does not descend from deployed {}",
+                        name, new_sha, prev_sha
+                    )
+                } else {
+                    println!(
+                        "{}: history rewritten; {} does not descend from deployed {}",
+                        name, new_sha, prev_sha
+                    )
+                }
+            }
             WorkloadEvent::ActionOutput(name, source_type, data) => match source_type {
                 SourceType::StdOut => println!("{}: {}", name, String::from_utf8_lossy(&data)),
                 SourceType::StdErr => eprintln!("{}: {}", name, String::from_utf8_lossy(&data)),
@@ -37,6 +109,11 @@ pub fn logging_receiver(events: &Receiver<WorkloadEvent>) {
             WorkloadEvent::ActionExit(name, exit) => {
                 println!("{}: exited with code {}", name, exit)
             }
+            WorkloadEvent::ActionFinished(name, result, attempts) => match result {
+                ActionResult::Success => println!("{}: succeeded", name),
+                ActionResult::Failure => println!("{}: failed after {} attempt(s)", name, attempts),
+                ActionResult::Skipped => println!("{}: skipped, when condition not met", name),
+            },
             WorkloadEvent::Success(name, new_sha) => {
                 println!("{}: actions successful for {}", name, new_sha)
             }
@@ -46,7 +123,62 @@ pub fn logging_receiver(events: &Receiver<WorkloadEvent>) {
             WorkloadEvent::Error(name, error, new_sha) => {
                 println!("{}: error running actions for {}: {}", name, new_sha, error)
             }
-            WorkloadEvent::Timeout(name) => println!("{}: took too long", name),
+            WorkloadEvent::RollingBack(name, failed_sha, target_sha) => {
+                println!(
+                    "{}: rolling back from {} to {}",
+                    name, failed_sha, target_sha
+                )
+            }
+            WorkloadEvent::RolledBack(name, failed_sha, target_sha, None) => {
+                println!(
+                    "{}: rolled back from {} to {}",
+                    name, failed_sha, target_sha
+                )
+            }
+            WorkloadEvent::RolledBack(name, failed_sha, target_sha, Some(reason)) => {
+                println!(
+                    "{}: rollback from {} to {} failed: {}",
+                    name, failed_sha, target_sha, reason
+                )
+            }
+            WorkloadEvent::HookFailed(name, reason) => {
+                println!("{}: hook failed: {}", name, reason)
+            }
+            WorkloadEvent::Timeout(name, Termination::Graceful(exit)) => {
+                println!(
+                    "{}: took too long, exited with code {} on SIGTERM",
+                    name, exit
+                )
+            }
+            WorkloadEvent::Timeout(name, Termination::Killed) => {
+                println!("{}: took too long, killed after grace period", name)
+            }
+            WorkloadEvent::ContainerKillFailed(name, container, reason) => {
+                println!(
+                    "{}: failed to kill container {}: {}",
+                    name, container, reason
+                )
+            }
+            WorkloadEvent::LeaseFailed(name, operation, reason) => {
+                let operation = match operation {
+                    LeaseOperation::Acquire => "acquire",
+                    LeaseOperation::Renew => "renew",
+                    LeaseOperation::Release => "release",
+                };
+                println!("{}: failed to {} lease: {}", name, operation, reason)
+            }
+            WorkloadEvent::LeaseLost(name) => {
+                println!("{}: lease taken over by another instance", name)
+            }
+            WorkloadEvent::ReloadFailed(name, reason) => {
+                println!("{}: failed to reload state: {}", name, reason)
+            }
+            WorkloadEvent::PersistFailed(name, reason) => {
+                println!("{}: failed to persist state: {}", name, reason)
+            }
+            WorkloadEvent::StateRecovered(name, reason) => {
+                println!("{}: state restored from backup: {}", name, reason)
+            }
         }
     }
 }
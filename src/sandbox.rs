@@ -0,0 +1,543 @@
+use std::{path::Path, process::Command};
+
+use crate::{config::SandboxConfig, errors::GitOpsError};
+
+/// Run the command in new mount, PID and (unless networking is allowed)
+/// network namespaces, with every mount read-only except for the workdir,
+/// the paths in `hide` covered up, under resource limits and optionally as
+/// another user.
+///
+/// Everything else on the host stays readable, e.g. kitops' state file and
+/// config, home directories and other tasks' workdirs; list such paths in
+/// `hide`. Within the namespace, a minimal init forwards SIGTERM and SIGINT
+/// to the action's process group and reaps orphans.
+#[cfg(target_os = "linux")]
+pub fn confine(
+    command: &mut Command,
+    config: &SandboxConfig,
+    workdir: &Path,
+) -> Result<(), GitOpsError> {
+    use std::os::unix::process::CommandExt;
+
+    let plan = linux::Plan::new(config, workdir).map_err(GitOpsError::ActionError)?;
+    unsafe {
+        command.pre_exec(move || linux::enter_sandbox(&plan));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn confine(
+    _command: &mut Command,
+    _config: &SandboxConfig,
+    _workdir: &Path,
+) -> Result<(), GitOpsError> {
+    Err(GitOpsError::ActionError(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "sandboxed actions require Linux",
+    )))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        ffi::CString,
+        io, mem,
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+        ptr,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    use crate::config::SandboxConfig;
+
+    const ROOT: &[u8] = b"/\0";
+    const PROC: &[u8] = b"/proc\0";
+    const PROC_FS: &[u8] = b"proc\0";
+    const TMPFS: &[u8] = b"tmpfs\0";
+    const DEV_NULL: &[u8] = b"/dev/null\0";
+    const FORWARDED_SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+    /// Process (or with a negative value, process group) that the signal
+    /// handler of the stand-in and of the init passes signals on to.
+    static FORWARD_TO: AtomicI32 = AtomicI32::new(0);
+
+    /// Everything enter_sandbox needs, prepared before fork since it cannot allocate.
+    pub(super) struct Plan {
+        config: SandboxConfig,
+        workdir: CString,
+        /// Mount points on the host and the flags to keep when remounting them read-only
+        mounts: Vec<(CString, libc::c_ulong)>,
+        /// Paths to cover up and whether they are directories
+        hide: Vec<(CString, bool)>,
+        /// Group to switch to: the configured one or else the user's primary group
+        gid: Option<libc::gid_t>,
+    }
+
+    impl Plan {
+        pub(super) fn new(config: &SandboxConfig, workdir: &Path) -> io::Result<Self> {
+            let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+            let mounts = parse_mountinfo(&mountinfo)
+                .into_iter()
+                .map(|(mount_point, flags)| Ok((cstring(&mount_point)?, flags)))
+                .collect::<io::Result<_>>()?;
+            let mut hide = Vec::new();
+            for path in &config.hide {
+                // Nothing to hide
+                let Ok(metadata) = std::fs::metadata(path) else {
+                    continue;
+                };
+                if workdir.starts_with(path) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("cannot hide {}, which holds the workdir", path.display()),
+                    ));
+                }
+                hide.push((cstring(path)?, metadata.is_dir()));
+            }
+            let gid = match (config.user, config.group) {
+                (_, Some(group)) => Some(group),
+                (Some(user), None) => Some(primary_gid(user)?),
+                (None, None) => None,
+            };
+            Ok(Plan {
+                config: config.clone(),
+                workdir: cstring(workdir)?,
+                mounts,
+                hide,
+                gid,
+            })
+        }
+    }
+
+    /// Looked up before fork, since NSS may allocate or open files.
+    fn primary_gid(uid: libc::uid_t) -> io::Result<libc::gid_t> {
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut buf = vec![0 as libc::c_char; 16384];
+        let mut result = ptr::null_mut();
+        let err =
+            unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        if result.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no user with uid {}, so the sandbox needs a group", uid),
+            ));
+        }
+        Ok(passwd.pw_gid)
+    }
+
+    fn cstring(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes()).map_err(Into::into)
+    }
+
+    /// Mount points and their per-mount flags, in mount order.
+    fn parse_mountinfo(mountinfo: &str) -> Vec<(PathBuf, libc::c_ulong)> {
+        mountinfo
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(' ').skip(4);
+                let mount_point = fields.next()?;
+                let options = fields.next()?;
+                let flags = options
+                    .split(',')
+                    .map(|option| match option {
+                        "nosuid" => libc::MS_NOSUID,
+                        "nodev" => libc::MS_NODEV,
+                        "noexec" => libc::MS_NOEXEC,
+                        "noatime" => libc::MS_NOATIME,
+                        "nodiratime" => libc::MS_NODIRATIME,
+                        "relatime" => libc::MS_RELATIME,
+                        _ => 0,
+                    })
+                    .fold(0, |flags, flag| flags | flag);
+                Some((PathBuf::from(unescape(mount_point)), flags))
+            })
+            .collect()
+    }
+
+    /// Mountinfo escapes space, tab, newline and backslash as octal.
+    fn unescape(field: &str) -> String {
+        let mut unescaped = String::with_capacity(field.len());
+        let mut rest = field;
+        while let Some(pos) = rest.find('\\') {
+            unescaped.push_str(&rest[..pos]);
+            let code = rest
+                .get(pos + 1..pos + 4)
+                .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+            match code {
+                Some(code) => {
+                    unescaped.push(char::from(code));
+                    rest = &rest[pos + 4..];
+                }
+                None => {
+                    unescaped.push('\\');
+                    rest = &rest[pos + 1..];
+                }
+            }
+        }
+        unescaped.push_str(rest);
+        unescaped
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    fn mount(
+        source: *const u8,
+        target: *const u8,
+        fstype: *const u8,
+        flags: libc::c_ulong,
+    ) -> io::Result<()> {
+        check(unsafe {
+            libc::mount(
+                source.cast(),
+                target.cast(),
+                fstype.cast(),
+                flags,
+                ptr::null(),
+            )
+        })
+        .map(|_| ())
+    }
+
+    /// Runs in the forked child between fork and exec, so it must not allocate.
+    pub(super) fn enter_sandbox(plan: &Plan) -> io::Result<()> {
+        let config = &plan.config;
+        if let Some(bytes) = config.memory_limit {
+            let limit = libc::rlimit {
+                rlim_cur: bytes,
+                rlim_max: bytes,
+            };
+            check(unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) })?;
+        }
+        if let Some(cpu_time) = config.cpu_time_limit {
+            let secs = cpu_time.as_secs().max(1);
+            let limit = libc::rlimit {
+                rlim_cur: secs,
+                rlim_max: secs,
+            };
+            check(unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) })?;
+        }
+        let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        if !config.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        check(unsafe { libc::unshare(flags) })?;
+        // Keep our mount changes from propagating back to the host
+        mount(
+            ptr::null(),
+            ROOT.as_ptr(),
+            ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+        )?;
+        for (mount_point, flags) in &plan.mounts {
+            let res = mount(
+                ptr::null(),
+                mount_point.as_ptr().cast(),
+                ptr::null(),
+                libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags,
+            );
+            match res {
+                // Unmounted since we read mountinfo
+                Err(err) if matches!(err.raw_os_error(), Some(libc::ENOENT | libc::EINVAL)) => (),
+                res => res?,
+            }
+        }
+        for (path, is_dir) in &plan.hide {
+            let path = path.as_ptr().cast();
+            if *is_dir {
+                mount(
+                    TMPFS.as_ptr(),
+                    path,
+                    TMPFS.as_ptr(),
+                    libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                )?;
+            } else {
+                mount(DEV_NULL.as_ptr(), path, ptr::null(), libc::MS_BIND)?;
+                mount(
+                    ptr::null(),
+                    path,
+                    ptr::null(),
+                    libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+                )?;
+            }
+        }
+        // As a mount of its own, the workdir can be writable when the rest is not
+        let workdir = plan.workdir.as_ptr().cast();
+        mount(workdir, workdir, ptr::null(), libc::MS_BIND | libc::MS_REC)?;
+        mount(
+            ptr::null(),
+            workdir,
+            ptr::null(),
+            libc::MS_REMOUNT | libc::MS_BIND | libc::MS_NOSUID | libc::MS_NODEV,
+        )?;
+        // Our working directory still refers to the read-only mount underneath
+        check(unsafe { libc::chdir(plan.workdir.as_ptr()) })?;
+
+        // Signals arriving before the handlers are in place would kill the
+        // stand-in or init without passing them on
+        let mut forwarded: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe {
+            libc::sigemptyset(&mut forwarded);
+            for signal in FORWARDED_SIGNALS {
+                libc::sigaddset(&mut forwarded, signal);
+            }
+        }
+        check(unsafe { libc::sigprocmask(libc::SIG_BLOCK, &forwarded, ptr::null_mut()) })?;
+
+        // Only children of this process enter the new PID namespace, so fork
+        // and let this process stand in for the action; kitops waits for it
+        // and signals its process group.
+        let init = check(unsafe { libc::fork() })?;
+        if init > 0 {
+            supervise(init, &forwarded);
+        }
+        // The namespace ends with its init, so it goes down with the stand-in
+        check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+        // kitops' signals reach the stand-in only, which passes them on once
+        check(unsafe { libc::setpgid(0, 0) })?;
+        mount(
+            PROC_FS.as_ptr(),
+            PROC.as_ptr(),
+            PROC_FS.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        )?;
+        if config.user.is_some() || plan.gid.is_some() {
+            // Otherwise the action keeps root's supplementary groups
+            check(unsafe { libc::setgroups(0, ptr::null()) })?;
+        }
+        // Before setuid, which gives up the right to change groups
+        if let Some(gid) = plan.gid {
+            check(unsafe { libc::setgid(gid) })?;
+        }
+        if let Some(user) = config.user {
+            check(unsafe { libc::setuid(user) })?;
+        }
+        // PID 1 ignores signals it has no handler for, so the action must not be init
+        let action = check(unsafe { libc::fork() })?;
+        if action > 0 {
+            unsafe { libc::setpgid(action, action) };
+            supervise(-action, &forwarded);
+        }
+        check(unsafe { libc::setpgid(0, 0) })?;
+        check(unsafe { libc::sigprocmask(libc::SIG_UNBLOCK, &forwarded, ptr::null_mut()) })?;
+        Ok(())
+    }
+
+    extern "C" fn forward_signal(signal: libc::c_int) {
+        let target = FORWARD_TO.load(Ordering::Relaxed);
+        if target != 0 {
+            unsafe { libc::kill(target, signal) };
+        }
+    }
+
+    /// Pass signals on to `target` until the process it names exits, then
+    /// exit the same way. Never returns to std, so closes every fd it
+    /// inherited: holding std's exec status pipe would keep spawn() waiting
+    /// and holding the output pipes would keep kitops reading.
+    fn supervise(target: libc::pid_t, forwarded: &libc::sigset_t) -> ! {
+        FORWARD_TO.store(target, Ordering::Relaxed);
+        close_all_fds();
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = forward_signal as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut action.sa_mask);
+            for signal in FORWARDED_SIGNALS {
+                libc::sigaction(signal, &action, ptr::null_mut());
+            }
+            libc::sigprocmask(libc::SIG_UNBLOCK, forwarded, ptr::null_mut());
+        }
+        let pid = target.abs();
+        let mut status = 0;
+        loop {
+            // As init, also reap orphans reparented to us
+            let reaped = unsafe { libc::waitpid(-1, &mut status, 0) };
+            if reaped == pid {
+                break;
+            }
+            if reaped < 0 && io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                unsafe { libc::_exit(1) };
+            }
+        }
+        let code = if libc::WIFEXITED(status) {
+            libc::WEXITSTATUS(status)
+        } else {
+            128 + libc::WTERMSIG(status)
+        };
+        unsafe { libc::_exit(code) }
+    }
+
+    fn close_all_fds() {
+        let (first, last, flags): (libc::c_uint, libc::c_uint, libc::c_uint) = (0, !0, 0);
+        let res = unsafe { libc::syscall(libc::SYS_close_range, first, last, flags) };
+        if res < 0 {
+            // Kernels before 5.9
+            let mut limit: libc::rlimit = unsafe { mem::zeroed() };
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+            for fd in 0..limit.rlim_cur.min(65536) {
+                unsafe { libc::close(fd as libc::c_int) };
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::path::PathBuf;
+
+        use super::parse_mountinfo;
+
+        #[test]
+        fn parse_mount_points_and_flags() {
+            let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:21 / /dev/shm rw,nosuid,nodev shared:2 - tmpfs tmpfs rw
+24 22 8:2 / /mnt/ze\\040disk ro,noexec - ext4 /dev/sda2 ro
+";
+            assert_eq!(
+                parse_mountinfo(mountinfo),
+                vec![
+                    (PathBuf::from("/"), libc::MS_RELATIME),
+                    (PathBuf::from("/dev/shm"), libc::MS_NOSUID | libc::MS_NODEV),
+                    (PathBuf::from("/mnt/ze disk"), libc::MS_NOEXEC),
+                ]
+            );
+        }
+    }
+}
+
+/// These need root for namespaces and mounts; run them with
+/// `sudo -E cargo test -- --ignored sandbox`.
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        actions::{run_action, Action, ActionResult},
+        config::ActionConfig,
+        receiver::{Termination, WorkloadEvent},
+    };
+
+    fn sandboxed_action(script: &str) -> Action {
+        sandboxed_action_with(script, "")
+    }
+
+    fn sandboxed_action_with(script: &str, sandbox: &str) -> Action {
+        let config: ActionConfig = serde_yaml::from_str(&format!(
+            r#"
+name: test
+entrypoint: /bin/sh
+args: [-c, {}]
+termination_grace_period: 2s
+sandbox:
+  hide: [/etc/hostname]
+{}
+"#,
+            serde_json::to_string(script).unwrap(),
+            sandbox
+        ))
+        .unwrap();
+        Action::new(config)
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn sandboxed_action_is_confined() {
+        let action = sandboxed_action(
+            "test $$ != 1 && ! touch /kitops-sandbox-probe && ! touch /dev/shm/kitops-sandbox-probe \
+             && test ! -s /etc/hostname && touch ok",
+        );
+        let workdir = tempfile::tempdir().unwrap();
+        let sink = Arc::new(Mutex::new(move |_| Ok(())));
+        let res = run_action(
+            "test",
+            &action,
+            workdir.path(),
+            Instant::now() + Duration::from_secs(5),
+            &|| false,
+            &sink,
+        );
+        assert!(matches!(res, Ok(ActionResult::Success)));
+        assert!(workdir.path().join("ok").exists());
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn sandboxed_user_without_group_drops_root_groups() {
+        // nobody, whose primary group is not root's
+        let action = sandboxed_action_with("id -u; id -g; id -G", "  user: 65534");
+        let workdir = tempfile::tempdir().unwrap();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let output2 = output.clone();
+        let sink = Arc::new(Mutex::new(move |event| {
+            if let WorkloadEvent::ActionOutput(_, _, data) = event {
+                output2.lock().unwrap().extend(data);
+            }
+            Ok(())
+        }));
+        let res = run_action(
+            "test",
+            &action,
+            workdir.path(),
+            Instant::now() + Duration::from_secs(5),
+            &|| false,
+            &sink,
+        );
+        assert!(matches!(res, Ok(ActionResult::Success)));
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let ids = output.lines().collect::<Vec<_>>();
+        assert_eq!(ids[0], "65534");
+        assert_ne!(ids[1], "0");
+        // The primary group only, no supplementary groups
+        assert_eq!(ids[2], ids[1]);
+    }
+
+    #[test]
+    #[ignore = "needs root"]
+    fn sandboxed_action_with_long_output_times_out() {
+        // More output than fits in a pipe, then more time than the deadline allows
+        let action = sandboxed_action(
+            "trap 'echo terminated; exit 1' TERM; head -c 1000000 /dev/zero; sleep 10 & wait",
+        );
+        let workdir = tempfile::tempdir().unwrap();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let output2 = output.clone();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = events.clone();
+        let sink = Arc::new(Mutex::new(move |event| {
+            if let WorkloadEvent::ActionOutput(_, _, ref data) = event {
+                output2.lock().unwrap().extend_from_slice(data);
+            }
+            events2.lock().unwrap().push(event);
+            Ok(())
+        }));
+        let started = Instant::now();
+        let res = run_action(
+            "test",
+            &action,
+            workdir.path(),
+            started + Duration::from_secs(1),
+            &|| false,
+            &sink,
+        );
+        assert!(matches!(res, Ok(ActionResult::Failure)));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        let output = output.lock().unwrap();
+        assert_eq!(output.len(), 1000000 + "terminated\n".len());
+        assert!(output.ends_with(b"terminated\n"));
+        assert!(matches!(
+            events.lock().unwrap().last(),
+            Some(WorkloadEvent::Timeout(_, Termination::Graceful(_)))
+        ));
+    }
+}
@@ -0,0 +1,981 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+    },
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
+};
+
+use gix::ObjectId;
+
+use crate::{
+    errors::GitOpsError,
+    lease::Lease,
+    receiver::{LeaseOperation, WorkloadEvent},
+    state::State,
+    store::Store,
+    task::ScheduledTask,
+    utils::find_cycle,
+    workload::Workload,
+};
+
+/// Reasons for the scheduler to wake up before the next task is due.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wakeup {
+    /// The task at this index has completed its run
+    Finished(usize),
+    /// Something outside the scheduler needs attention, e.g. a shutdown signal
+    External,
+}
+
+/// Transient errors persisting state, e.g. a network glitch talking to a
+/// remote store, are retried this many times with increasing delay.
+const PERSIST_ATTEMPTS: u32 = 3;
+const PERSIST_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Where the scheduler keeps task state. Plain closures persist and never
+/// reload; reloading matters when other instances share the tasks.
+pub trait Persist<W: Workload + Clone + Send + 'static> {
+    fn persist(&mut self, task: &ScheduledTask<W>) -> Result<(), GitOpsError>;
+    /// The stored state of the task, which may have changed since we last saw it.
+    fn reload(&mut self, _task: &ScheduledTask<W>) -> Result<Option<State>, GitOpsError> {
+        Ok(None)
+    }
+}
+
+impl<F, W> Persist<W> for F
+where
+    F: FnMut(&ScheduledTask<W>) -> Result<(), GitOpsError>,
+    W: Workload + Clone + Send + 'static,
+{
+    fn persist(&mut self, task: &ScheduledTask<W>) -> Result<(), GitOpsError> {
+        self(task)
+    }
+}
+
+/// Persist task state to a store.
+pub struct StorePersist<'a>(pub &'a mut dyn Store);
+
+impl<W: Workload + Clone + Send + 'static> Persist<W> for StorePersist<'_> {
+    fn persist(&mut self, task: &ScheduledTask<W>) -> Result<(), GitOpsError> {
+        self.0.persist(task.id(), &task.state)
+    }
+
+    fn reload(&mut self, task: &ScheduledTask<W>) -> Result<Option<State>, GitOpsError> {
+        self.0.reload(&task.id())
+    }
+}
+
+pub struct Scheduler {
+    max_concurrent_runs: usize,
+    once_only: bool,
+    grace_period: Duration,
+    lease: Option<Box<dyn Lease>>,
+    waker: Sender<Wakeup>,
+    wakeups: Receiver<Wakeup>,
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent_runs: usize, once_only: bool, grace_period: Duration) -> Self {
+        let (waker, wakeups) = channel();
+        Self {
+            max_concurrent_runs: max_concurrent_runs.max(1),
+            once_only,
+            grace_period,
+            lease: None,
+            waker,
+            wakeups,
+        }
+    }
+
+    /// Only run tasks while holding their lease, so that several instances
+    /// can share the same tasks.
+    #[must_use]
+    pub fn with_lease(mut self, lease: Box<dyn Lease>) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+
+    pub fn waker(&self) -> Sender<Wakeup> {
+        self.waker.clone()
+    }
+
+    pub fn run<P, W>(
+        &self,
+        tasks: &mut [ScheduledTask<W>],
+        mut persist: P,
+        shutdown: &AtomicBool,
+    ) -> Result<(), GitOpsError>
+    where
+        P: Persist<W>,
+        W: Workload + Clone + Send + 'static,
+    {
+        let dependencies = resolve_dependencies(tasks)?;
+        for (idx, task) in tasks.iter_mut().enumerate() {
+            let waker = self.waker.clone();
+            task.set_waker(move || {
+                let _ = waker.send(Wakeup::Finished(idx));
+            });
+        }
+        let mut started = HashSet::new();
+        let mut shutdown_deadline = None;
+        let mut renew_at = Instant::now();
+        loop {
+            if shutdown_deadline.is_none() && shutdown.load(Ordering::Relaxed) {
+                shutdown_deadline = Some(Instant::now() + self.grace_period);
+            }
+            self.finalize_finished(tasks, &mut persist)?;
+            if shutdown_deadline.is_none() {
+                self.start_eligible(tasks, &dependencies, &mut persist, &mut started)?;
+            }
+            let busy = tasks.iter().filter(|t| is_busy(t)).count();
+            if let Some(lease) = &self.lease {
+                if busy > 0 && Instant::now() >= renew_at {
+                    renew_leases(lease.as_ref(), tasks);
+                    renew_at = Instant::now() + lease.duration() / 3;
+                }
+            }
+            let timeout = if let Some(deadline) = shutdown_deadline {
+                if busy == 0 {
+                    return Ok(());
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(GitOpsError::ShutdownTimeout(busy));
+                }
+                Some(deadline - now)
+            } else {
+                // Nothing running means nothing due could be started
+                if self.once_only && busy == 0 {
+                    return Ok(());
+                }
+                // Tasks that are due but were not started are waiting for a slot,
+                // a repo or a dependency, which only changes when some task finishes.
+                let now = SystemTime::now();
+                tasks
+                    .iter()
+                    .filter(|t| !is_busy(t))
+                    .filter_map(|t| t.state.next_run.duration_since(now).ok())
+                    .min()
+            };
+            let timeout = match (&self.lease, busy) {
+                (Some(_), 1..) => {
+                    let renewal = renew_at.saturating_duration_since(Instant::now());
+                    Some(timeout.map_or(renewal, |t| t.min(renewal)))
+                }
+                _ => timeout,
+            };
+            match self.wait(timeout) {
+                Some(Wakeup::Finished(idx)) if is_busy(&tasks[idx]) => {
+                    self.finalize_task(&mut tasks[idx], &mut persist)?;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn wait(&self, timeout: Option<Duration>) -> Option<Wakeup> {
+        match timeout {
+            Some(timeout) => self.wakeups.recv_timeout(timeout).ok(),
+            None => self.wakeups.recv().ok(),
+        }
+    }
+
+    /// Start due tasks, earliest first, as long as there are free slots, no
+    /// other task is using the same repo and dependencies have settled.
+    fn start_eligible<P, W>(
+        &self,
+        tasks: &mut [ScheduledTask<W>],
+        dependencies: &[Vec<usize>],
+        persist: &mut P,
+        started: &mut HashSet<usize>,
+    ) -> Result<(), GitOpsError>
+    where
+        P: Persist<W>,
+        W: Workload + Clone + Send + 'static,
+    {
+        let mut busy_repos = tasks
+            .iter()
+            .filter(|t| is_busy(t))
+            .map(ScheduledTask::repo_id)
+            .collect::<HashSet<_>>();
+        let mut running = tasks.iter().filter(|t| is_busy(t)).count();
+        let mut candidates = tasks
+            .iter()
+            .enumerate()
+            .filter(|(idx, t)| t.is_eligible() && !(self.once_only && started.contains(idx)))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|idx| tasks[*idx].state.next_run);
+        for idx in candidates {
+            if running >= self.max_concurrent_runs {
+                break;
+            }
+            let Some(required_sha) = self.required_sha(tasks, idx, dependencies, started) else {
+                continue;
+            };
+            let task = &mut tasks[idx];
+            if busy_repos.contains(&task.repo_id()) {
+                continue;
+            }
+            if !self.acquire_lease(task)? {
+                // Another instance is running the task; check back next interval
+                task.schedule_next();
+                continue;
+            }
+            if self.lease.is_some() && !self.reload_state(task, persist)? {
+                continue;
+            }
+            busy_repos.insert(task.repo_id());
+            task.require_sha(required_sha);
+            task.start()?;
+            task.schedule_next();
+            persist_task(task, persist)?;
+            started.insert(idx);
+            running += 1;
+        }
+        Ok(())
+    }
+
+    fn acquire_lease<W: Workload + Clone + Send + 'static>(
+        &self,
+        task: &ScheduledTask<W>,
+    ) -> Result<bool, GitOpsError> {
+        let Some(lease) = &self.lease else {
+            return Ok(true);
+        };
+        match lease.acquire(&task.id()) {
+            Ok(acquired) => Ok(acquired),
+            Err(err) if err.is_fatal() => Err(err),
+            Err(err) => {
+                task.notify(WorkloadEvent::LeaseFailed(
+                    task.id(),
+                    LeaseOperation::Acquire,
+                    format!("{}", err),
+                ));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Another instance may have run the task before we got its lease.
+    /// Returns false, releasing the lease, if the task is no longer due.
+    fn reload_state<P, W>(
+        &self,
+        task: &mut ScheduledTask<W>,
+        persist: &mut P,
+    ) -> Result<bool, GitOpsError>
+    where
+        P: Persist<W>,
+        W: Workload + Clone + Send + 'static,
+    {
+        match persist.reload(task) {
+            Ok(Some(state)) => task.set_state(state),
+            Ok(None) => (),
+            Err(err) if err.is_fatal() => return Err(err),
+            Err(err) => {
+                task.notify(WorkloadEvent::ReloadFailed(task.id(), format!("{}", err)));
+                task.schedule_next();
+            }
+        }
+        if task.is_eligible() {
+            return Ok(true);
+        }
+        self.release_lease(task);
+        Ok(false)
+    }
+
+    fn finalize_finished<P, W>(
+        &self,
+        tasks: &mut [ScheduledTask<W>],
+        persist: &mut P,
+    ) -> Result<(), GitOpsError>
+    where
+        P: Persist<W>,
+        W: Workload + Clone + Send + 'static,
+    {
+        for task in tasks.iter_mut().filter(|t| t.is_finished()) {
+            self.finalize_task(task, persist)?;
+        }
+        Ok(())
+    }
+
+    fn finalize_task<P, W>(
+        &self,
+        task: &mut ScheduledTask<W>,
+        persist: &mut P,
+    ) -> Result<(), GitOpsError>
+    where
+        P: Persist<W>,
+        W: Workload + Clone + Send + 'static,
+    {
+        let result = match task.finalize() {
+            Err(err) if err.is_fatal() => Err(err),
+            // The instance that took over the task owns its state now
+            _ if task.is_interrupted() => return Ok(()),
+            _ => persist_task(task, persist),
+        };
+        self.release_lease(task);
+        result
+    }
+
+    fn release_lease<W: Workload + Clone + Send + 'static>(&self, task: &ScheduledTask<W>) {
+        if let Some(lease) = &self.lease {
+            // Not fatal, since the lease expires on its own
+            if let Err(err) = lease.release(&task.id()) {
+                task.notify(WorkloadEvent::LeaseFailed(
+                    task.id(),
+                    LeaseOperation::Release,
+                    format!("{}", err),
+                ));
+            }
+        }
+    }
+
+    /// Returns None while a dependency is running, about to run, has not
+    /// succeeded or has given up on a commit. Otherwise, returns the commit
+    /// that dependencies on the same repo succeeded with, which is the only
+    /// commit the task may deploy.
+    fn required_sha<W: Workload + Clone + Send + 'static>(
+        &self,
+        tasks: &[ScheduledTask<W>],
+        idx: usize,
+        dependencies: &[Vec<usize>],
+        started: &HashSet<usize>,
+    ) -> Option<Option<ObjectId>> {
+        let mut required_sha = None;
+        for dep_idx in &dependencies[idx] {
+            let dep = &tasks[*dep_idx];
+            let pending = dep.is_eligible() && !(self.once_only && started.contains(dep_idx));
+            if is_busy(dep) || pending || dep.state.current_sha.is_null() {
+                return None;
+            }
+            if dep.repo_id() != tasks[idx].repo_id() {
+                if dep.state.consecutive_failures > 0 || dep.state.given_up_sha.is_some() {
+                    return None;
+                }
+            } else if required_sha
+                .replace(dep.state.current_sha)
+                .is_some_and(|sha| sha != dep.state.current_sha)
+            {
+                return None;
+            }
+        }
+        Some(required_sha)
+    }
+}
+
+/// Map each task's depends_on to task indices, refusing unknown tasks and cycles.
+fn resolve_dependencies<W: Workload + Clone + Send + 'static>(
+    tasks: &[ScheduledTask<W>],
+) -> Result<Vec<Vec<usize>>, GitOpsError> {
+    let indices = tasks
+        .iter()
+        .enumerate()
+        .map(|(idx, t)| (t.id(), idx))
+        .collect::<HashMap<_, _>>();
+    let dependencies = tasks
+        .iter()
+        .map(|t| {
+            t.depends_on()
+                .into_iter()
+                .map(|dep| {
+                    indices
+                        .get(&dep)
+                        .copied()
+                        .ok_or_else(|| GitOpsError::UnknownDependency(t.id(), dep))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(idx) = find_cycle(&dependencies) {
+        return Err(GitOpsError::DependencyCycle(tasks[idx].id()));
+    }
+    Ok(dependencies)
+}
+
+/// Fatal errors are passed on. Otherwise the task keeps its state in memory
+/// and persists it again on its next run.
+fn persist_task<P, W>(task: &ScheduledTask<W>, persist: &mut P) -> Result<(), GitOpsError>
+where
+    P: Persist<W>,
+    W: Workload + Clone + Send + 'static,
+{
+    let mut attempt = 1;
+    loop {
+        match persist.persist(task) {
+            Err(err) if err.is_fatal() => return Err(err),
+            Err(_) if attempt < PERSIST_ATTEMPTS => {
+                sleep(PERSIST_RETRY_DELAY * attempt);
+                attempt += 1;
+            }
+            Err(err) => {
+                task.notify(WorkloadEvent::PersistFailed(task.id(), format!("{}", err)));
+                return Ok(());
+            }
+            Ok(()) => return Ok(()),
+        }
+    }
+}
+
+fn is_busy<W: Workload + Clone + Send + 'static>(task: &ScheduledTask<W>) -> bool {
+    task.is_running() || task.is_finished()
+}
+
+/// Extend leases on running tasks so that other instances do not take over,
+/// and interrupt runs whose lease another instance has taken.
+fn renew_leases<W: Workload + Clone + Send + 'static>(
+    lease: &dyn Lease,
+    tasks: &[ScheduledTask<W>],
+) {
+    for task in tasks.iter().filter(|t| is_busy(t)) {
+        match lease.acquire(&task.id()) {
+            Ok(true) => (),
+            Ok(false) => {
+                task.notify(WorkloadEvent::LeaseLost(task.id()));
+                task.interrupt();
+            }
+            Err(err) => task.notify(WorkloadEvent::LeaseFailed(
+                task.id(),
+                LeaseOperation::Renew,
+                format!("{}", err),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        sync::atomic::{AtomicBool, Ordering},
+        thread::{scope, sleep},
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use gix::{hash::Kind, ObjectId};
+
+    use crate::{
+        errors::GitOpsError, receiver::WorkloadEvent, state::State, task::ScheduledTask,
+        testutils::TestWorkload,
+    };
+
+    #[cfg(unix)]
+    use crate::lease::{FileLease, Lease};
+
+    use super::{Persist, Scheduler, Wakeup};
+
+    fn noop_persist(_t: &ScheduledTask<TestWorkload>) -> Result<(), GitOpsError> {
+        Ok(())
+    }
+
+    #[test]
+    fn run_eligible_task() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::default())];
+        let mut persist = noop_persist;
+        scheduler
+            .start_eligible(&mut tasks[..], &[vec![]], &mut persist, &mut HashSet::new())
+            .unwrap();
+        assert!(tasks[0].is_running());
+        tasks[0].await_finished();
+        scheduler
+            .finalize_finished(&mut tasks[..], &mut persist)
+            .unwrap();
+        assert!(!tasks[0].is_finished());
+        assert!(tasks[0].state().current_sha.is_empty_blob());
+    }
+
+    #[test]
+    fn dont_start_ineligible_task() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::default())];
+        tasks[0].set_state(State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            next_run: SystemTime::now() + Duration::from_secs(1),
+            ..Default::default()
+        });
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &[vec![]],
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(!tasks[0].is_running());
+    }
+
+    #[test]
+    fn failing_task_keeps_current_sha() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::fail_with(|| {
+            GitOpsError::ActionFailed(
+                "ze-task".to_owned(),
+                "ze-action".to_owned(),
+                ObjectId::empty_blob(Kind::Sha1),
+            )
+        }))];
+        let mut persist = noop_persist;
+        scheduler
+            .start_eligible(&mut tasks[..], &[vec![]], &mut persist, &mut HashSet::new())
+            .unwrap();
+        tasks[0].await_finished();
+        scheduler
+            .finalize_finished(&mut tasks[..], &mut persist)
+            .unwrap();
+        assert_eq!(tasks[0].state().current_sha, ObjectId::null(Kind::Sha1));
+    }
+
+    #[test]
+    fn transient_error_does_not_stop_tasks() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::fail_with(|| {
+            GitOpsError::FetchError("network unreachable".into())
+        }))];
+        tasks[0].start().unwrap();
+        tasks[0].await_finished();
+        scheduler
+            .finalize_finished(&mut tasks[..], &mut noop_persist)
+            .unwrap();
+    }
+
+    #[test]
+    fn fatal_error_stops_tasks() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::fail_with(|| {
+            GitOpsError::SavingState(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        }))];
+        tasks[0].start().unwrap();
+        tasks[0].await_finished();
+        let res = scheduler.finalize_finished(&mut tasks[..], &mut noop_persist);
+        assert!(matches!(res, Err(GitOpsError::SavingState(_))));
+    }
+
+    #[test]
+    fn limit_concurrent_runs() {
+        let scheduler = Scheduler::new(2, false, Duration::ZERO);
+        let mut tasks = ["a", "b", "c"]
+            .iter()
+            .map(|repo| ScheduledTask::new(TestWorkload::default().with_repo(repo)))
+            .collect::<Vec<_>>();
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &[vec![], vec![], vec![]],
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert_eq!(tasks.iter().filter(|t| t.is_running()).count(), 2);
+    }
+
+    #[test]
+    fn one_run_per_repo() {
+        let scheduler = Scheduler::new(5, false, Duration::ZERO);
+        let mut tasks = ["a", "a", "b"]
+            .iter()
+            .map(|repo| ScheduledTask::new(TestWorkload::default().with_repo(repo)))
+            .collect::<Vec<_>>();
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &[vec![], vec![], vec![]],
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(tasks[0].is_running());
+        assert!(!tasks[1].is_running());
+        assert!(tasks[2].is_running());
+    }
+
+    #[test]
+    fn start_most_overdue_task_first() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let mut tasks = ["a", "b"]
+            .iter()
+            .map(|repo| ScheduledTask::new(TestWorkload::default().with_repo(repo)))
+            .collect::<Vec<_>>();
+        tasks[1].set_state(State {
+            next_run: SystemTime::now() - Duration::from_secs(10),
+            ..Default::default()
+        });
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &[vec![], vec![]],
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(!tasks[0].is_running());
+        assert!(tasks[1].is_running());
+    }
+
+    #[test]
+    fn once_only_runs_each_task_once() {
+        let scheduler = Scheduler::new(1, true, Duration::ZERO);
+        let mut tasks = ["a", "b"]
+            .iter()
+            .map(|repo| {
+                ScheduledTask::new(
+                    TestWorkload::default()
+                        .with_repo(repo)
+                        .with_interval(Duration::ZERO),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut runs = 0;
+        let persist = |t: &ScheduledTask<TestWorkload>| {
+            if t.is_running() {
+                runs += 1;
+            }
+            Ok(())
+        };
+        scheduler
+            .run(&mut tasks[..], persist, &AtomicBool::new(false))
+            .unwrap();
+        assert_eq!(runs, 2);
+        assert!(tasks.iter().all(|t| t.state().current_sha.is_empty_blob()));
+    }
+
+    #[test]
+    fn shutdown_waits_for_running_task() {
+        let scheduler = Scheduler::new(1, false, Duration::from_secs(5));
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::default())];
+        let mut persisted = 0;
+        let persist = |_t: &ScheduledTask<TestWorkload>| {
+            persisted += 1;
+            Ok(())
+        };
+        let shutdown = AtomicBool::new(false);
+        let waker = scheduler.waker();
+        let started = Instant::now();
+        scope(|s| {
+            s.spawn(|| {
+                sleep(Duration::from_millis(5));
+                shutdown.store(true, Ordering::Relaxed);
+                waker.send(Wakeup::External).unwrap();
+            });
+            scheduler.run(&mut tasks[..], persist, &shutdown).unwrap();
+        });
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(persisted, 2);
+        assert!(tasks[0].state().current_sha.is_empty_blob());
+    }
+
+    #[test]
+    fn shutdown_gives_up_after_grace_period() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::default())];
+        tasks[0].start().unwrap();
+        let res = scheduler.run(&mut tasks[..], noop_persist, &AtomicBool::new(true));
+        assert!(matches!(res, Err(GitOpsError::ShutdownTimeout(1))));
+    }
+
+    #[test]
+    fn shutdown_does_not_start_tasks() {
+        let scheduler = Scheduler::new(1, false, Duration::from_secs(5));
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::default())];
+        scheduler
+            .run(&mut tasks[..], noop_persist, &AtomicBool::new(true))
+            .unwrap();
+        assert!(!tasks[0].is_running());
+        assert!(tasks[0].is_eligible());
+    }
+
+    fn dependent_tasks() -> Vec<ScheduledTask<TestWorkload>> {
+        dependent_tasks_on_repos("a", "b").1
+    }
+
+    /// Returns the dependent's workload too, to observe what it was required to deploy.
+    fn dependent_tasks_on_repos(
+        apps_repo: &str,
+        infra_repo: &str,
+    ) -> (TestWorkload, Vec<ScheduledTask<TestWorkload>>) {
+        let apps = TestWorkload::default()
+            .with_id("apps")
+            .with_repo(apps_repo)
+            .with_dependencies(&["infra"]);
+        let tasks = vec![
+            ScheduledTask::new(apps.clone()),
+            ScheduledTask::new(
+                TestWorkload::default()
+                    .with_id("infra")
+                    .with_repo(infra_repo),
+            ),
+        ];
+        (apps, tasks)
+    }
+
+    #[test]
+    fn refuse_unknown_dependency() {
+        let tasks = vec![ScheduledTask::new(
+            TestWorkload::default().with_dependencies(&["nonesuch"]),
+        )];
+        let res = super::resolve_dependencies(&tasks);
+        assert!(matches!(res, Err(GitOpsError::UnknownDependency(..))));
+    }
+
+    #[test]
+    fn refuse_dependency_cycle() {
+        let tasks = vec![
+            ScheduledTask::new(
+                TestWorkload::default()
+                    .with_id("a")
+                    .with_dependencies(&["b"]),
+            ),
+            ScheduledTask::new(
+                TestWorkload::default()
+                    .with_id("b")
+                    .with_dependencies(&["a"]),
+            ),
+        ];
+        let res = super::resolve_dependencies(&tasks);
+        assert!(matches!(res, Err(GitOpsError::DependencyCycle(..))));
+    }
+
+    #[test]
+    fn dependency_runs_first() {
+        let scheduler = Scheduler::new(5, false, Duration::ZERO);
+        let (apps, mut tasks) = dependent_tasks_on_repos("a", "b");
+        let dependencies = super::resolve_dependencies(&tasks).unwrap();
+        let mut persist = noop_persist;
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &dependencies,
+                &mut persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(!tasks[0].is_running());
+        assert!(tasks[1].is_running());
+        tasks[1].await_finished();
+        scheduler
+            .finalize_finished(&mut tasks[..], &mut persist)
+            .unwrap();
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &dependencies,
+                &mut persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(tasks[0].is_running());
+        // Different repos do not share commits
+        assert_eq!(apps.required_sha(), None);
+    }
+
+    #[test]
+    fn dependent_on_same_repo_requires_dependency_sha() {
+        let scheduler = Scheduler::new(5, true, Duration::ZERO);
+        let (apps, mut tasks) = dependent_tasks_on_repos("a", "a");
+        scheduler
+            .run(&mut tasks[..], noop_persist, &AtomicBool::new(false))
+            .unwrap();
+        assert_eq!(apps.required_sha(), Some(ObjectId::empty_blob(Kind::Sha1)));
+    }
+
+    #[test]
+    fn failing_dependency_blocks_dependent() {
+        let scheduler = Scheduler::new(5, false, Duration::ZERO);
+        let mut tasks = dependent_tasks();
+        tasks[1].set_state(State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            next_run: SystemTime::now() + Duration::from_secs(1),
+            consecutive_failures: 1,
+            ..Default::default()
+        });
+        let dependencies = super::resolve_dependencies(&tasks).unwrap();
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &dependencies,
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(!tasks[0].is_running());
+        assert!(!tasks[1].is_running());
+    }
+
+    #[test]
+    fn given_up_dependency_blocks_dependent() {
+        let scheduler = Scheduler::new(5, false, Duration::ZERO);
+        let mut tasks = dependent_tasks();
+        // Runs after giving up succeed without deploying anything
+        tasks[1].set_state(State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            next_run: SystemTime::now() + Duration::from_secs(1),
+            given_up_sha: Some(ObjectId::empty_tree(Kind::Sha1)),
+            ..Default::default()
+        });
+        let dependencies = super::resolve_dependencies(&tasks).unwrap();
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &dependencies,
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(!tasks[0].is_running());
+        assert!(!tasks[1].is_running());
+    }
+
+    #[test]
+    fn once_only_runs_dependent_after_dependency() {
+        let scheduler = Scheduler::new(5, true, Duration::ZERO);
+        let mut tasks = dependent_tasks();
+        scheduler
+            .run(&mut tasks[..], noop_persist, &AtomicBool::new(false))
+            .unwrap();
+        assert!(tasks[0].state().current_sha.is_empty_blob());
+        assert!(tasks[1].state().current_sha.is_empty_blob());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skip_task_leased_by_other_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let other =
+            FileLease::new(dir.path(), "other".to_owned(), Duration::from_secs(60)).unwrap();
+        assert!(other.acquire("test").unwrap());
+        let lease = FileLease::new(dir.path(), "us".to_owned(), Duration::from_secs(60)).unwrap();
+        let scheduler = Scheduler::new(1, false, Duration::ZERO).with_lease(Box::new(lease));
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::default())];
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &[vec![]],
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(!tasks[0].is_running());
+        assert!(!tasks[0].is_eligible());
+        other.release("test").unwrap();
+        tasks[0].await_eligible();
+        scheduler
+            .start_eligible(
+                &mut tasks[..],
+                &[vec![]],
+                &mut noop_persist,
+                &mut HashSet::new(),
+            )
+            .unwrap();
+        assert!(tasks[0].is_running());
+        assert!(!other.acquire("test").unwrap());
+        tasks[0].await_finished();
+        scheduler
+            .finalize_finished(&mut tasks[..], &mut noop_persist)
+            .unwrap();
+        assert!(other.acquire("test").unwrap());
+    }
+
+    /// A store that another instance has written to.
+    struct SharedState(State);
+
+    impl Persist<TestWorkload> for SharedState {
+        fn persist(&mut self, task: &ScheduledTask<TestWorkload>) -> Result<(), GitOpsError> {
+            self.0 = task.state();
+            Ok(())
+        }
+
+        fn reload(
+            &mut self,
+            _task: &ScheduledTask<TestWorkload>,
+        ) -> Result<Option<State>, GitOpsError> {
+            Ok(Some(self.0.clone()))
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reload_state_after_acquiring_lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let lease = FileLease::new(dir.path(), "us".to_owned(), Duration::from_secs(60)).unwrap();
+        let scheduler = Scheduler::new(1, false, Duration::ZERO).with_lease(Box::new(lease));
+        let mut tasks = vec![ScheduledTask::new(TestWorkload::default())];
+        let mut store = SharedState(State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            next_run: SystemTime::now() + Duration::from_secs(60),
+            ..Default::default()
+        });
+        scheduler
+            .start_eligible(&mut tasks[..], &[vec![]], &mut store, &mut HashSet::new())
+            .unwrap();
+        assert!(!tasks[0].is_running());
+        assert!(tasks[0].state().current_sha.is_empty_blob());
+        let other =
+            FileLease::new(dir.path(), "other".to_owned(), Duration::from_secs(60)).unwrap();
+        assert!(other.acquire("test").unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn interrupt_run_when_lease_is_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let lease = FileLease::new(dir.path(), "us".to_owned(), Duration::ZERO).unwrap();
+        let scheduler = Scheduler::new(1, false, Duration::ZERO).with_lease(Box::new(lease));
+        let work = TestWorkload::default();
+        let mut tasks = vec![ScheduledTask::new(work.clone())];
+        let mut persisted = 0;
+        let mut persist = |_t: &ScheduledTask<TestWorkload>| {
+            persisted += 1;
+            Ok(())
+        };
+        scheduler
+            .start_eligible(&mut tasks[..], &[vec![]], &mut persist, &mut HashSet::new())
+            .unwrap();
+        assert!(tasks[0].is_running());
+        let other =
+            FileLease::new(dir.path(), "other".to_owned(), Duration::from_secs(60)).unwrap();
+        assert!(other.acquire("test").unwrap());
+        super::renew_leases(scheduler.lease.as_deref().unwrap(), &tasks[..]);
+        assert!(tasks[0].is_interrupted());
+        assert_eq!(
+            work.events(),
+            vec![WorkloadEvent::LeaseLost("test".to_owned())]
+        );
+        tasks[0].await_finished();
+        scheduler
+            .finalize_finished(&mut tasks[..], &mut persist)
+            .unwrap();
+        assert_eq!(persisted, 1);
+        assert!(tasks[0].state().current_sha.is_null());
+        assert_eq!(tasks[0].state().failed_attempts, 0);
+    }
+
+    #[test]
+    fn retry_transient_persist_errors() {
+        let scheduler = Scheduler::new(1, false, Duration::ZERO);
+        let work = TestWorkload::default();
+        let mut tasks = vec![ScheduledTask::new(work.clone())];
+        tasks[0].start().unwrap();
+        tasks[0].await_finished();
+        let mut attempts = 0;
+        let mut persist = |_t: &ScheduledTask<TestWorkload>| {
+            attempts += 1;
+            Err::<(), _>(GitOpsError::S3ApiError(
+                "https://example.com/bucket/state.yaml".to_owned(),
+                reqwest::StatusCode::SERVICE_UNAVAILABLE,
+                "SlowDown".to_owned(),
+            ))
+        };
+        scheduler
+            .finalize_finished(&mut tasks[..], &mut persist)
+            .unwrap();
+        assert_eq!(attempts, super::PERSIST_ATTEMPTS);
+        assert!(tasks[0].state().current_sha.is_empty_blob());
+        assert!(matches!(
+            work.events()[..],
+            [WorkloadEvent::PersistFailed(ref name, _)] if name == "test"
+        ));
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{config::DecryptConfig, errors::GitOpsError};
+
+/// Decrypt matching files in the worktree in place with sops, so that actions
+/// see plaintext. The repo itself only ever holds the encrypted files.
+pub fn decrypt_worktree(config: &DecryptConfig, workdir: &Path) -> Result<(), GitOpsError> {
+    let mut files = Vec::new();
+    find_encrypted(config, workdir, &mut files)?;
+    files.sort();
+    for file in files {
+        let output = Command::new(&config.sops)
+            .arg("--decrypt")
+            .arg("--in-place")
+            .arg(&file)
+            .env("SOPS_AGE_KEY_FILE", &config.age_identity)
+            .current_dir(workdir)
+            .output()
+            .map_err(|err| decrypt_failed(workdir, &file, err.to_string()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(decrypt_failed(workdir, &file, stderr.trim().to_owned()));
+        }
+    }
+    Ok(())
+}
+
+fn find_encrypted(
+    config: &DecryptConfig,
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), GitOpsError> {
+    for entry in std::fs::read_dir(dir).map_err(GitOpsError::WorkDir)? {
+        let entry = entry.map_err(GitOpsError::WorkDir)?;
+        let file_type = entry.file_type().map_err(GitOpsError::WorkDir)?;
+        let path = entry.path();
+        if file_type.is_dir() {
+            find_encrypted(config, &path, files)?;
+        } else if file_type.is_file() && config.matches(&entry.file_name().to_string_lossy()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn decrypt_failed(workdir: &Path, file: &Path, reason: String) -> GitOpsError {
+    let file = file.strip_prefix(workdir).unwrap_or(file);
+    GitOpsError::DecryptFailed(file.to_owned(), reason)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, path::Path};
+
+    use crate::{config::DecryptConfig, errors::GitOpsError};
+
+    use super::decrypt_worktree;
+
+    fn fake_sops(dir: &Path, script: &str) -> String {
+        let path = dir.join("fake-sops");
+        std::fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn decrypt_config(sops: String) -> DecryptConfig {
+        DecryptConfig {
+            age_identity: "/ze/keys.txt".into(),
+            suffixes: DecryptConfig::default_suffixes(),
+            sops,
+        }
+    }
+
+    #[test]
+    fn decrypt_matching_files() {
+        let bin = tempfile::tempdir().unwrap();
+        let workdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(workdir.path().join("sub")).unwrap();
+        std::fs::write(workdir.path().join("sub/secret.sops.yaml"), "encrypted").unwrap();
+        std::fs::write(workdir.path().join("plain.yaml"), "plain").unwrap();
+        std::fs::write(workdir.path().join(".sops.yaml"), "creation_rules: []").unwrap();
+        let config = decrypt_config(fake_sops(
+            bin.path(),
+            "echo \"$SOPS_AGE_KEY_FILE\" > \"$3\"",
+        ));
+        decrypt_worktree(&config, workdir.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(workdir.path().join("sub/secret.sops.yaml")).unwrap(),
+            "/ze/keys.txt\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(workdir.path().join("plain.yaml")).unwrap(),
+            "plain"
+        );
+        assert_eq!(
+            std::fs::read_to_string(workdir.path().join(".sops.yaml")).unwrap(),
+            "creation_rules: []"
+        );
+    }
+
+    #[test]
+    fn report_failed_decryption() {
+        let bin = tempfile::tempdir().unwrap();
+        let workdir = tempfile::tempdir().unwrap();
+        std::fs::write(workdir.path().join("secret.sops.json"), "encrypted").unwrap();
+        let config = decrypt_config(fake_sops(bin.path(), "echo 'no key' >&2; exit 128"));
+        let res = decrypt_worktree(&config, workdir.path());
+        assert!(matches!(
+            res,
+            Err(GitOpsError::DecryptFailed(file, reason))
+                if file == Path::new("secret.sops.json") && reason == "no key"
+        ));
+    }
+}
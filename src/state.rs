@@ -3,10 +3,48 @@ use std::time::SystemTime;
 use gix::{hash::Kind, ObjectId};
 use serde::{Deserialize, Serialize};
 
+/// Outcome of the most recent run of a task.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RunResult {
+    Success,
+    /// An action ran but did not succeed
+    Failure {
+        action: String,
+        message: String,
+    },
+    /// The run could not complete, e.g. because the repo could not be fetched
+    Error {
+        message: String,
+    },
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct State {
     pub next_run: SystemTime,
     pub current_sha: ObjectId,
+    /// Failed action runs counted against the actions retry policy; reset on
+    /// success or when giving up
+    #[serde(default)]
+    pub failed_attempts: u32,
+    /// Failed fetches counted against the fetch retry policy; reset once a fetch succeeds
+    #[serde(default)]
+    pub failed_fetches: u32,
+    /// Commit whose actions exhausted their retries; skipped until a new commit arrives
+    #[serde(default)]
+    pub given_up_sha: Option<ObjectId>,
+    /// Failed runs since the last successful one
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// Most recent commit that actions were run for, whether they succeeded or not
+    #[serde(default)]
+    pub last_attempted_sha: Option<ObjectId>,
+    #[serde(default)]
+    pub last_result: Option<RunResult>,
+    #[serde(default)]
+    pub last_run_start: Option<SystemTime>,
+    #[serde(default)]
+    pub last_run_end: Option<SystemTime>,
 }
 
 impl Default for State {
@@ -14,6 +52,14 @@ impl Default for State {
         Self {
             current_sha: ObjectId::null(Kind::Sha1),
             next_run: SystemTime::now(),
+            failed_attempts: 0,
+            failed_fetches: 0,
+            given_up_sha: None,
+            consecutive_failures: 0,
+            last_attempted_sha: None,
+            last_result: None,
+            last_run_start: None,
+            last_run_end: None,
         }
     }
 }
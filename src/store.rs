@@ -1,40 +1,169 @@
 use std::{
     collections::{HashMap, HashSet},
+    ffi::OsString,
     fs::File,
+    io::{ErrorKind, Write},
     path::{Path, PathBuf},
 };
 
-use crate::{errors::GitOpsError, state::State, task::ScheduledTask, workload::Workload};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::{errors::GitOpsError, state::State};
 
 pub trait Store {
     fn get(&self, id: &str) -> Option<&State>;
+    fn ids(&self) -> Vec<String>;
+    /// Forget tasks not in the set; takes effect with the next write.
     fn retain(&mut self, task_ids: HashSet<String>);
-    fn persist<W: Workload + Clone + Send + 'static>(
-        &mut self,
-        id: String,
-        task: &ScheduledTask<W>,
-    ) -> Result<(), GitOpsError>;
+    fn persist(&mut self, id: String, state: &State) -> Result<(), GitOpsError>;
+    fn remove(&mut self, id: &str) -> Result<(), GitOpsError>;
+    /// Re-read a task's state from storage, picking up changes made by other
+    /// instances or `kitops state` since we loaded it.
+    fn reload(&mut self, id: &str) -> Result<Option<State>, GitOpsError>;
+    /// Why state was restored from a backup rather than loaded as stored, if it was.
+    fn recovery(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Set the state for a task or, given None, remove it.
+pub(crate) fn apply_update(tasks: &mut HashMap<String, State>, id: &str, state: Option<&State>) {
+    match state {
+        Some(state) => {
+            tasks.insert(id.to_owned(), state.clone());
+        }
+        None => {
+            tasks.remove(id);
+        }
+    }
+}
+
+/// Bump when the state document changes shape and add a migration from the
+/// previous version to MIGRATIONS.
+const STATE_VERSION: u64 = 1;
+
+/// Migrations indexed by the version they upgrade from.
+const MIGRATIONS: [fn(Value) -> Value; STATE_VERSION as usize] = [migrate_unversioned];
+
+/// Version 0 was a bare map from task id to state.
+fn migrate_unversioned(tasks: Value) -> Value {
+    let tasks = match tasks {
+        Value::Null => Value::Mapping(Mapping::new()),
+        tasks => tasks,
+    };
+    let mut doc = Mapping::new();
+    doc.insert("version".into(), 1.into());
+    doc.insert("tasks".into(), tasks);
+    Value::Mapping(doc)
 }
 
+#[derive(Serialize)]
+struct VersionedState<'a> {
+    version: u64,
+    tasks: &'a HashMap<String, State>,
+}
+
+#[derive(Deserialize)]
+struct StateDocument {
+    tasks: HashMap<String, State>,
+}
+
+/// The state document shared by all stores, mapping task id to state.
+pub(crate) fn serialize_state(state: &HashMap<String, State>) -> Result<Vec<u8>, GitOpsError> {
+    let doc = VersionedState {
+        version: STATE_VERSION,
+        tasks: state,
+    };
+    serde_yaml::to_string(&doc)
+        .map(String::into_bytes)
+        .map_err(GitOpsError::SerdeState)
+}
+
+/// Reads documents written by this or earlier versions of kitops.
+pub(crate) fn deserialize_state(buf: &[u8]) -> Result<HashMap<String, State>, GitOpsError> {
+    let mut doc: Value = serde_yaml::from_slice(buf).map_err(GitOpsError::SerdeState)?;
+    // A task named "version" in an unversioned document holds a map, not a number
+    let mut version = doc.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if version > STATE_VERSION {
+        return Err(GitOpsError::UnsupportedStateVersion(version));
+    }
+    while version < STATE_VERSION {
+        doc = MIGRATIONS[version as usize](doc);
+        version += 1;
+    }
+    serde_yaml::from_value::<StateDocument>(doc)
+        .map(|doc| doc.tasks)
+        .map_err(GitOpsError::SerdeState)
+}
+
+/// State in a local file. Writes go to a temporary file which replaces the
+/// state file once synced, and the previous state file is kept as backup.
 #[derive(Debug, Default)]
 pub struct FileStore {
     path: PathBuf,
     state: HashMap<String, State>,
+    recovery: Option<String>,
 }
 
 impl FileStore {
     pub fn from_file(path: &Path) -> Result<Self, GitOpsError> {
-        let state = if path.try_exists().map_err(GitOpsError::StateFile)? {
-            let file = File::open(path).map_err(GitOpsError::LoadingState)?;
-            serde_yaml::from_reader(file).map_err(GitOpsError::SerdeState)?
-        } else {
-            HashMap::new()
+        let backup = with_suffix(path, ".bak");
+        let mut recovery = None;
+        let state = match Self::read(path) {
+            Ok(Some(state)) => state,
+            Ok(None) => match Self::read(&backup)? {
+                Some(state) => {
+                    recovery = Some(format!(
+                        "{} is missing, recovered from {}",
+                        path.display(),
+                        backup.display()
+                    ));
+                    state
+                }
+                None => HashMap::new(),
+            },
+            Err(err) => match Self::read(&backup) {
+                Ok(Some(state)) => {
+                    recovery = Some(format!(
+                        "failed to load {} ({}), recovered from {}",
+                        path.display(),
+                        err,
+                        backup.display()
+                    ));
+                    state
+                }
+                _ => return Err(err),
+            },
         };
         Ok(FileStore {
             path: path.to_path_buf(),
             state,
+            recovery,
         })
     }
+
+    fn read(path: &Path) -> Result<Option<HashMap<String, State>>, GitOpsError> {
+        if !path.try_exists().map_err(GitOpsError::StateFile)? {
+            return Ok(None);
+        }
+        let buf = std::fs::read(path).map_err(GitOpsError::LoadingState)?;
+        deserialize_state(&buf).map(Some)
+    }
+
+    fn write(&self, buf: &[u8]) -> std::io::Result<()> {
+        let tmp = with_suffix(&self.path, ".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(buf)?;
+        file.sync_all()?;
+        // The state file stays in place until atomically replaced, so readers
+        // such as `kitops state` never find it missing
+        if self.path.try_exists()? {
+            keep_backup(&self.path, &with_suffix(&self.path, ".bak"))?;
+        }
+        std::fs::rename(&tmp, &self.path)?;
+        sync_parent(&self.path)
+    }
 }
 
 impl Store for FileStore {
@@ -42,17 +171,186 @@ impl Store for FileStore {
         self.state.get(id)
     }
 
+    fn ids(&self) -> Vec<String> {
+        self.state.keys().cloned().collect()
+    }
+
     fn retain(&mut self, task_ids: HashSet<String>) {
         self.state.retain(|id, _| task_ids.contains(id));
     }
 
-    fn persist<W: Workload + Clone + Send + 'static>(
-        &mut self,
-        id: String,
-        task: &ScheduledTask<W>,
-    ) -> Result<(), GitOpsError> {
-        self.state.insert(id, task.state());
-        let file = File::create(&self.path).map_err(GitOpsError::SavingState)?;
-        serde_yaml::to_writer(file, &self.state).map_err(GitOpsError::SerdeState)
+    fn persist(&mut self, id: String, state: &State) -> Result<(), GitOpsError> {
+        apply_update(&mut self.state, &id, Some(state));
+        let buf = serialize_state(&self.state)?;
+        self.write(&buf).map_err(GitOpsError::SavingState)
+    }
+
+    fn remove(&mut self, id: &str) -> Result<(), GitOpsError> {
+        apply_update(&mut self.state, id, None);
+        let buf = serialize_state(&self.state)?;
+        self.write(&buf).map_err(GitOpsError::SavingState)
+    }
+
+    fn reload(&mut self, id: &str) -> Result<Option<State>, GitOpsError> {
+        if let Some(tasks) = Self::read(&self.path)? {
+            apply_update(&mut self.state, id, tasks.get(id));
+        }
+        Ok(self.state.get(id).cloned())
+    }
+
+    fn recovery(&self) -> Option<&str> {
+        self.recovery.as_deref()
+    }
+}
+
+/// Hard link the current file as backup, or copy it where links are not supported.
+fn keep_backup(path: &Path, backup: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(backup) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => (),
+    }
+    if std::fs::hard_link(path, backup).is_err() {
+        std::fs::copy(path, backup)?;
+        File::open(backup)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Make the renames durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gix::{hash::Kind, ObjectId};
+
+    use crate::{errors::GitOpsError, state::State};
+
+    use super::{deserialize_state, serialize_state, with_suffix, FileStore, Store};
+
+    fn state() -> State {
+        State {
+            current_sha: ObjectId::empty_blob(Kind::Sha1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn state_document_roundtrip() {
+        let tasks = HashMap::from([("ze-task".to_owned(), state())]);
+        let buf = serialize_state(&tasks).unwrap();
+        assert!(String::from_utf8_lossy(&buf).starts_with("version: 1\n"));
+        let tasks = deserialize_state(&buf).unwrap();
+        assert!(tasks["ze-task"].current_sha.is_empty_blob());
+    }
+
+    #[test]
+    fn read_state_without_run_history() {
+        let mut state = serde_yaml::to_value(state()).unwrap();
+        for key in [
+            "consecutive_failures",
+            "last_attempted_sha",
+            "last_result",
+            "last_run_start",
+            "last_run_end",
+        ] {
+            state.as_mapping_mut().unwrap().remove(key);
+        }
+        let buf = serde_yaml::to_string(&HashMap::from([("ze-task", state)])).unwrap();
+        let tasks = deserialize_state(buf.as_bytes()).unwrap();
+        assert_eq!(tasks["ze-task"].consecutive_failures, 0);
+        assert_eq!(tasks["ze-task"].last_result, None);
+    }
+
+    #[test]
+    fn migrate_unversioned_state() {
+        let buf = serde_yaml::to_string(&HashMap::from([("version".to_owned(), state())])).unwrap();
+        let tasks = deserialize_state(buf.as_bytes()).unwrap();
+        assert!(tasks["version"].current_sha.is_empty_blob());
+        assert!(deserialize_state(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn refuse_newer_state_version() {
+        let res = deserialize_state(b"version: 999\ntasks: {}\n");
+        assert!(matches!(
+            res,
+            Err(GitOpsError::UnsupportedStateVersion(999))
+        ));
+    }
+
+    #[test]
+    fn file_store_keeps_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.yaml");
+        let mut store = FileStore::from_file(&path).unwrap();
+        store.persist("first".to_owned(), &state()).unwrap();
+        store.persist("second".to_owned(), &state()).unwrap();
+        assert!(!with_suffix(&path, ".tmp").exists());
+        let backup = FileStore::from_file(&with_suffix(&path, ".bak")).unwrap();
+        assert!(backup.get("first").is_some());
+        assert!(backup.get("second").is_none());
+        store.persist("third".to_owned(), &state()).unwrap();
+        let current = FileStore::from_file(&path).unwrap();
+        assert!(current.get("third").is_some());
+        let backup = FileStore::from_file(&with_suffix(&path, ".bak")).unwrap();
+        assert!(backup.get("second").is_some());
+        assert!(backup.get("third").is_none());
+    }
+
+    #[test]
+    fn file_store_recovers_from_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.yaml");
+        let mut store = FileStore::from_file(&path).unwrap();
+        store.persist("first".to_owned(), &state()).unwrap();
+        store.persist("second".to_owned(), &state()).unwrap();
+        std::fs::write(&path, "version: 1\ntasks: [trunc").unwrap();
+        let store = FileStore::from_file(&path).unwrap();
+        assert!(store.get("first").is_some());
+        assert!(store
+            .recovery()
+            .is_some_and(|r| r.contains("state.yaml.bak")));
+    }
+
+    #[test]
+    fn file_store_recovers_missing_file_from_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.yaml");
+        let mut store = FileStore::from_file(&path).unwrap();
+        assert!(store.recovery().is_none());
+        store.persist("first".to_owned(), &state()).unwrap();
+        store.persist("second".to_owned(), &state()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let store = FileStore::from_file(&path).unwrap();
+        assert!(store.get("first").is_some());
+        assert!(store.recovery().is_some_and(|r| r.contains("missing")));
+    }
+
+    #[test]
+    fn file_store_fails_without_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.yaml");
+        std::fs::write(&path, "version: 1\ntasks: [trunc").unwrap();
+        let res = FileStore::from_file(&path);
+        assert!(matches!(res, Err(GitOpsError::SerdeState(_))));
     }
 }
@@ -1,17 +1,40 @@
 use std::{
+    any::Any,
     ops::Add,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread::{spawn, JoinHandle},
     time::SystemTime,
 };
 
 use gix::ObjectId;
 
-use crate::{errors::GitOpsError, state::State, workload::Workload};
+use crate::{
+    errors::GitOpsError,
+    receiver::WorkloadEvent,
+    state::{RunResult, State},
+    workload::{RunOutcome, Workload},
+};
 
 pub struct ScheduledTask<W: Workload + Clone + Send> {
     work: W,
     pub state: State,
-    worker: Option<JoinHandle<Result<ObjectId, GitOpsError>>>,
+    worker: Option<JoinHandle<Result<RunOutcome, GitOpsError>>>,
+    waker: Option<Arc<dyn Fn() + Send + Sync>>,
+    interrupted: Arc<AtomicBool>,
+}
+
+/// Calls the waker when the worker exits, whether it returns or panics.
+struct WakeOnExit(Option<Arc<dyn Fn() + Send + Sync>>);
+
+impl Drop for WakeOnExit {
+    fn drop(&mut self) {
+        if let Some(waker) = &self.0 {
+            waker();
+        }
+    }
 }
 
 impl<W: Workload + Clone + Send + 'static> ScheduledTask<W> {
@@ -20,6 +43,8 @@ impl<W: Workload + Clone + Send + 'static> ScheduledTask<W> {
             work,
             state: State::default(),
             worker: None,
+            waker: None,
+            interrupted: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -27,6 +52,29 @@ impl<W: Workload + Clone + Send + 'static> ScheduledTask<W> {
         self.work.id()
     }
 
+    pub fn repo_id(&self) -> String {
+        self.work.repo_id()
+    }
+
+    pub fn depends_on(&self) -> Vec<String> {
+        self.work.depends_on()
+    }
+
+    /// Restrict the next run to deploying this commit.
+    pub fn require_sha(&mut self, sha: Option<ObjectId>) {
+        self.work.require_sha(sha);
+    }
+
+    /// Tell the task's watchers about something that happened outside a run.
+    pub fn notify(&self, event: WorkloadEvent) {
+        self.work.notify(event);
+    }
+
+    /// Called from the worker thread when a run completes.
+    pub fn set_waker(&mut self, waker: impl Fn() + Send + Sync + 'static) {
+        self.waker = Some(Arc::new(waker));
+    }
+
     pub fn is_eligible(&self) -> bool {
         self.worker.is_none() && SystemTime::now() >= self.state.next_run
     }
@@ -40,28 +88,122 @@ impl<W: Workload + Clone + Send + 'static> ScheduledTask<W> {
     }
 
     pub fn schedule_next(&mut self) {
-        self.state.next_run = SystemTime::now().add(self.work.interval());
+        self.state.next_run = self.work.next_run(SystemTime::now());
     }
 
     pub fn start(&mut self) -> Result<(), GitOpsError> {
-        let current_sha = self.state.current_sha;
+        self.state.last_run_start = Some(SystemTime::now());
+        let state = self.state.clone();
         let workdir = tempfile::tempdir()
             .map_err(GitOpsError::WorkDir)?
             .into_path();
-        let work = self.work.clone();
-        self.worker = Some(spawn(move || work.perform(workdir, current_sha)));
+        self.interrupted = Arc::new(AtomicBool::new(false));
+        let mut work = self.work.clone();
+        work.set_interrupt(self.interrupted.clone());
+        let wake_on_exit = WakeOnExit(self.waker.clone());
+        self.worker = Some(spawn(move || {
+            let _wake_on_exit = wake_on_exit;
+            work.perform(workdir, state)
+        }));
         Ok(())
     }
 
+    /// Ask the running worker to stop, e.g. because another instance has
+    /// taken over the task.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the current or last run was interrupted.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
     pub fn finalize(&mut self) -> Result<(), GitOpsError> {
-        let new_sha = self
+        let result = self
             .worker
             .take()
             .expect("result only called once")
             .join()
-            .expect("thread not to panic")?;
-        self.state.current_sha = new_sha;
-        Ok(())
+            .unwrap_or_else(|panic| Err(GitOpsError::WorkerPanic(panic_message(&*panic))));
+        self.state.last_run_end = Some(SystemTime::now());
+        match result {
+            Ok(RunOutcome::Deployed(new_sha)) => {
+                if new_sha != self.state.current_sha {
+                    self.state.given_up_sha = None;
+                    self.state.last_attempted_sha = Some(new_sha);
+                }
+                self.state.current_sha = new_sha;
+                self.state.failed_attempts = 0;
+                self.state.failed_fetches = 0;
+                self.state.consecutive_failures = 0;
+                self.state.last_result = Some(RunResult::Success);
+                Ok(())
+            }
+            // Nothing ran that could succeed or fail, so the last result stands
+            Ok(RunOutcome::Skipped) => {
+                self.state.failed_fetches = 0;
+                Ok(())
+            }
+            Err(err) => {
+                if !matches!(err, GitOpsError::ActionInterrupted(..)) {
+                    self.state.consecutive_failures += 1;
+                }
+                let cause = match &err {
+                    GitOpsError::RolledBack(_, cause) => cause.as_ref(),
+                    err => err,
+                };
+                self.state.last_result = Some(match cause {
+                    GitOpsError::ActionFailed(_, action, sha) => {
+                        self.state.last_attempted_sha = Some(*sha);
+                        RunResult::Failure {
+                            action: action.clone(),
+                            message: format!("{}", err),
+                        }
+                    }
+                    _ => RunResult::Error {
+                        message: format!("{}", err),
+                    },
+                });
+                self.register_failure(&err);
+                Err(err)
+            }
+        }
+    }
+
+    fn register_failure(&mut self, err: &GitOpsError) {
+        // Cut short by shutdown; the next run tries again as if this one never happened
+        if matches!(err, GitOpsError::ActionInterrupted(..)) {
+            return;
+        }
+        // Retrying would deploy the failed commit only to roll it back again,
+        // and a commit that failed verification will never pass it
+        if let GitOpsError::RolledBack(sha, _) | GitOpsError::UnverifiedCommit(sha, _) = err {
+            self.state.last_attempted_sha = Some(*sha);
+            self.state.given_up_sha = Some(*sha);
+            self.state.failed_attempts = 0;
+            return;
+        }
+        let retry = self.work.retry();
+        let (policy, attempts) = if err.is_action_failure() {
+            // Getting as far as actions means the fetch succeeded
+            self.state.failed_fetches = 0;
+            (&retry.actions, &mut self.state.failed_attempts)
+        } else {
+            (&retry.fetch, &mut self.state.failed_fetches)
+        };
+        *attempts += 1;
+        let attempts = *attempts;
+        if policy.max_attempts.map_or(true, |max| attempts < max) {
+            if let Some(backoff) = policy.backoff(attempts) {
+                self.state.next_run = SystemTime::now().add(backoff);
+            }
+        } else if policy.give_up {
+            if let Some(sha) = err.failed_sha() {
+                self.state.given_up_sha = Some(sha);
+                self.state.failed_attempts = 0;
+            }
+        }
     }
 
     pub fn state(&self) -> State {
@@ -71,10 +213,18 @@ impl<W: Workload + Clone + Send + 'static> ScheduledTask<W> {
     pub fn set_state(&mut self, state: State) {
         self.state = state;
         // If configuration has changed, this will move up the next run
-        self.state.next_run = std::cmp::min(
-            self.state.next_run,
-            SystemTime::now().add(self.work.interval()),
-        );
+        self.state.next_run =
+            std::cmp::min(self.state.next_run, self.work.next_run(SystemTime::now()));
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "<unknown panic>".to_owned()
     }
 }
 
@@ -85,9 +235,15 @@ mod tests {
         time::{Duration, SystemTime},
     };
 
-    use gix::ObjectId;
+    use gix::{hash::Kind, ObjectId};
 
-    use crate::{state::State, task::ScheduledTask, testutils::TestWorkload};
+    use crate::{
+        config::{RetryConfig, RetryPolicy},
+        errors::GitOpsError,
+        state::{RunResult, State},
+        task::ScheduledTask,
+        testutils::TestWorkload,
+    };
 
     #[test]
     fn scheduled_task_flow() {
@@ -105,17 +261,46 @@ mod tests {
         task.finalize().unwrap();
         assert!(!task.is_finished());
         assert!(task.state().current_sha.is_empty_blob());
+        assert_eq!(task.state().last_result, Some(RunResult::Success));
+        assert!(task.state().last_run_start <= task.state().last_run_end);
         task.await_eligible();
     }
 
     #[test]
-    #[should_panic]
+    fn skipped_run_keeps_last_result() {
+        let mut task = ScheduledTask::new(TestWorkload::default().skipping());
+        task.set_state(State {
+            consecutive_failures: 2,
+            failed_fetches: 1,
+            last_result: Some(RunResult::Error {
+                message: "ze-error".to_owned(),
+            }),
+            ..Default::default()
+        });
+        task.start().unwrap();
+        task.await_finished();
+        task.finalize().unwrap();
+        assert_eq!(task.state().consecutive_failures, 2);
+        assert_eq!(task.state().failed_fetches, 0);
+        assert!(matches!(
+            task.state().last_result,
+            Some(RunResult::Error { ref message }) if message == "ze-error"
+        ));
+    }
+
+    #[test]
     fn scheduled_task_on_panic() {
         let mut task = ScheduledTask::new(TestWorkload::fail_with(|| panic!("BOOM!")));
         task.start().unwrap();
         task.await_finished();
         assert!(!task.is_running());
-        task.finalize().unwrap();
+        let res = task.finalize();
+        assert!(matches!(res, Err(GitOpsError::WorkerPanic(ref msg)) if msg == "BOOM!"));
+        assert_eq!(task.state().failed_fetches, 1);
+        assert!(matches!(
+            task.state().last_result,
+            Some(RunResult::Error { ref message }) if message.contains("BOOM!")
+        ));
     }
 
     #[test]
@@ -124,6 +309,7 @@ mod tests {
         task.set_state(State {
             current_sha: ObjectId::null(gix::hash::Kind::Sha1),
             next_run: SystemTime::now() + Duration::from_millis(10),
+            ..Default::default()
         });
         assert!(!task.is_eligible());
         sleep(Duration::from_millis(10));
@@ -137,13 +323,186 @@ mod tests {
         task.set_state(State {
             current_sha: ObjectId::null(gix::hash::Kind::Sha1),
             next_run: stored_next_run,
+            ..Default::default()
         });
         assert!(task.state().next_run == stored_next_run);
         let stored_next_run = SystemTime::now() + Duration::from_secs(10);
         task.set_state(State {
             current_sha: ObjectId::null(gix::hash::Kind::Sha1),
             next_run: stored_next_run,
+            ..Default::default()
         });
         assert!(task.state().next_run < stored_next_run);
     }
+
+    fn action_failure() -> GitOpsError {
+        GitOpsError::ActionFailed(
+            "ze-task".to_owned(),
+            "ze-action".to_owned(),
+            ObjectId::empty_tree(Kind::Sha1),
+        )
+    }
+
+    #[test]
+    fn failing_task_backs_off() {
+        let retry = RetryConfig {
+            actions: RetryPolicy {
+                backoff_base: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut task =
+            ScheduledTask::new(TestWorkload::fail_with(action_failure).with_retry(retry));
+        task.start().unwrap();
+        task.schedule_next();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_attempts, 1);
+        assert!(task.state().next_run < SystemTime::now() + Duration::from_millis(100));
+        task.await_eligible();
+        task.start().unwrap();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_attempts, 2);
+        assert!(task.state().next_run > SystemTime::now() + Duration::from_millis(150));
+    }
+
+    #[test]
+    fn failing_task_falls_back_to_interval_after_max_attempts() {
+        let retry = RetryConfig {
+            fetch: RetryPolicy {
+                max_attempts: Some(1),
+                backoff_base: Some(Duration::from_millis(1)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut task = ScheduledTask::new(
+            TestWorkload::fail_with(|| GitOpsError::TestError).with_retry(retry),
+        );
+        task.start().unwrap();
+        task.schedule_next();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_fetches, 1);
+        assert!(task.state().next_run > SystemTime::now() + Duration::from_millis(500));
+        assert_eq!(task.state().given_up_sha, None);
+    }
+
+    #[test]
+    fn fetch_failures_do_not_count_as_action_attempts() {
+        let mut task = ScheduledTask::new(TestWorkload::fail_with(|| GitOpsError::TestError));
+        task.set_state(State {
+            failed_attempts: 2,
+            ..Default::default()
+        });
+        task.start().unwrap();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_attempts, 2);
+        assert_eq!(task.state().failed_fetches, 1);
+    }
+
+    #[test]
+    fn action_failure_resets_failed_fetches() {
+        let mut task = ScheduledTask::new(TestWorkload::fail_with(action_failure));
+        task.set_state(State {
+            failed_fetches: 2,
+            ..Default::default()
+        });
+        task.start().unwrap();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_attempts, 1);
+        assert_eq!(task.state().failed_fetches, 0);
+    }
+
+    #[test]
+    fn interrupted_task_does_not_count_as_failure() {
+        let retry = RetryConfig {
+            actions: RetryPolicy {
+                max_attempts: Some(1),
+                give_up: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut task = ScheduledTask::new(
+            TestWorkload::fail_with(|| GitOpsError::ActionInterrupted("ze-action".to_owned()))
+                .with_retry(retry),
+        );
+        task.start().unwrap();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_attempts, 0);
+        assert_eq!(task.state().consecutive_failures, 0);
+        assert_eq!(task.state().given_up_sha, None);
+    }
+
+    #[test]
+    fn failing_task_gives_up_on_sha() {
+        let retry = RetryConfig {
+            actions: RetryPolicy {
+                max_attempts: Some(1),
+                give_up: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut task =
+            ScheduledTask::new(TestWorkload::fail_with(action_failure).with_retry(retry));
+        task.start().unwrap();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_attempts, 0);
+        assert_eq!(task.state().consecutive_failures, 1);
+        assert_eq!(
+            task.state().given_up_sha,
+            Some(ObjectId::empty_tree(Kind::Sha1))
+        );
+        assert_eq!(
+            task.state().last_attempted_sha,
+            Some(ObjectId::empty_tree(Kind::Sha1))
+        );
+        assert!(matches!(
+            task.state().last_result,
+            Some(RunResult::Failure { ref action, .. }) if action == "ze-action"
+        ));
+    }
+
+    #[test]
+    fn rolled_back_sha_is_not_retried() {
+        let mut task = ScheduledTask::new(TestWorkload::fail_with(|| {
+            GitOpsError::RolledBack(ObjectId::empty_tree(Kind::Sha1), Box::new(action_failure()))
+        }));
+        task.start().unwrap();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_attempts, 0);
+        assert_eq!(task.state().consecutive_failures, 1);
+        assert_eq!(
+            task.state().given_up_sha,
+            Some(ObjectId::empty_tree(Kind::Sha1))
+        );
+        assert!(matches!(
+            task.state().last_result,
+            Some(RunResult::Failure { ref action, .. }) if action == "ze-action"
+        ));
+    }
+
+    #[test]
+    fn unverified_sha_is_not_retried() {
+        let mut task = ScheduledTask::new(TestWorkload::fail_with(|| {
+            GitOpsError::UnverifiedCommit(ObjectId::empty_tree(Kind::Sha1), "not signed".to_owned())
+        }));
+        task.start().unwrap();
+        task.await_finished();
+        assert!(task.finalize().is_err());
+        assert_eq!(task.state().failed_fetches, 0);
+        assert_eq!(
+            task.state().given_up_sha,
+            Some(ObjectId::empty_tree(Kind::Sha1))
+        );
+    }
 }
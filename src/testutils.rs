@@ -1,8 +1,26 @@
-use std::{path::PathBuf, sync::Arc, thread::sleep, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{sleep, spawn},
+    time::{Duration, SystemTime},
+};
 
 use gix::ObjectId;
 
-use crate::{errors::GitOpsError, task::ScheduledTask, workload::Workload};
+use crate::{
+    config::RetryConfig,
+    errors::GitOpsError,
+    receiver::WorkloadEvent,
+    state::State,
+    task::ScheduledTask,
+    workload::{RunOutcome, Workload},
+};
 
 impl<W: Workload + Clone + Send + 'static> ScheduledTask<W> {
     pub fn await_finished(&self) {
@@ -20,7 +38,16 @@ impl<W: Workload + Clone + Send + 'static> ScheduledTask<W> {
 
 #[derive(Clone, Default)]
 pub struct TestWorkload {
+    id: Option<String>,
+    dependencies: Vec<String>,
     errfunc: Option<Arc<Box<dyn Fn() -> GitOpsError + Send + Sync>>>,
+    retry: RetryConfig,
+    repo: String,
+    interval: Option<Duration>,
+    interrupted: Arc<AtomicBool>,
+    required_sha: Arc<Mutex<Option<ObjectId>>>,
+    events: Arc<Mutex<Vec<WorkloadEvent>>>,
+    skip: bool,
 }
 
 impl TestWorkload {
@@ -30,22 +57,185 @@ impl TestWorkload {
             ..Default::default()
         }
     }
+
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_repo(mut self, repo: &str) -> Self {
+        self.repo = repo.to_owned();
+        self
+    }
+
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_owned());
+        self
+    }
+
+    pub fn with_dependencies(mut self, dependencies: &[&str]) -> Self {
+        self.dependencies = dependencies.iter().map(|d| (*d).to_owned()).collect();
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Runs succeed without deploying anything, as when a commit was given up on.
+    pub fn skipping(mut self) -> Self {
+        self.skip = true;
+        self
+    }
+
+    /// The commit the scheduler last required; shared between clones.
+    pub fn required_sha(&self) -> Option<ObjectId> {
+        *self.required_sha.lock().unwrap()
+    }
+
+    /// Events notified outside runs; shared between clones.
+    pub fn events(&self) -> Vec<WorkloadEvent> {
+        self.events.lock().unwrap().clone()
+    }
 }
 
 impl Workload for TestWorkload {
     fn id(&self) -> String {
-        "test".to_string()
+        self.id.clone().unwrap_or_else(|| "test".to_string())
+    }
+
+    fn repo_id(&self) -> String {
+        self.repo.clone()
+    }
+
+    fn next_run(&self, now: SystemTime) -> SystemTime {
+        now + self.interval.unwrap_or(Duration::from_secs(1))
+    }
+
+    fn retry(&self) -> RetryConfig {
+        self.retry.clone()
+    }
+
+    fn depends_on(&self) -> Vec<String> {
+        self.dependencies.clone()
+    }
+
+    fn require_sha(&mut self, sha: Option<ObjectId>) {
+        *self.required_sha.lock().unwrap() = sha;
     }
 
-    fn interval(&self) -> Duration {
-        Duration::from_secs(1)
+    fn set_interrupt(&mut self, interrupted: Arc<AtomicBool>) {
+        self.interrupted = interrupted;
     }
 
-    fn perform(self, _workdir: PathBuf, _current_sha: ObjectId) -> Result<ObjectId, GitOpsError> {
+    fn notify(&self, event: WorkloadEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn perform(self, _workdir: PathBuf, _state: State) -> Result<RunOutcome, GitOpsError> {
         sleep(Duration::from_millis(10));
+        if self.interrupted.load(Ordering::Relaxed) {
+            return Err(GitOpsError::ActionInterrupted(self.id()));
+        }
         if self.errfunc.is_some() {
             return Err(self.errfunc.unwrap()());
         }
-        Ok(ObjectId::empty_blob(gix::hash::Kind::Sha1))
+        if self.skip {
+            return Ok(RunOutcome::Skipped);
+        }
+        Ok(RunOutcome::Deployed(ObjectId::empty_blob(
+            gix::hash::Kind::Sha1,
+        )))
     }
 }
+
+pub struct FakeRequest {
+    pub method: String,
+    /// Path and query, as sent
+    pub target: String,
+    /// Lowercased header names
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+pub struct FakeResponse {
+    pub status: &'static str,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl FakeResponse {
+    pub fn new(status: &'static str) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+/// Serve HTTP/1.1 on a random local port, one request per connection,
+/// for standing in for cloud services. Returns the base URL.
+pub fn fake_server(
+    mut handler: impl FnMut(FakeRequest) -> FakeResponse + Send + 'static,
+) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split(' ');
+            let method = parts.next().unwrap().to_owned();
+            let target = parts.next().unwrap().to_owned();
+            let mut headers = HashMap::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                match header.trim_end().split_once(':') {
+                    Some((name, value)) => {
+                        headers.insert(name.to_lowercase(), value.trim().to_owned())
+                    }
+                    None => break,
+                };
+            }
+            let length = headers
+                .get("content-length")
+                .map_or(0, |l| l.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let response = handler(FakeRequest {
+                method,
+                target,
+                headers,
+                body,
+            });
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                response.status,
+                response.body.len()
+            )
+            .unwrap();
+            for (name, value) in response.headers {
+                write!(stream, "{}: {}\r\n", name, value).unwrap();
+            }
+            stream.write_all(b"\r\n").unwrap();
+            stream.write_all(&response.body).unwrap();
+        }
+    });
+    endpoint
+}
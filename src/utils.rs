@@ -1,3 +1,5 @@
+use std::collections::{hash_map::RandomState, HashSet};
+use std::hash::{BuildHasher, Hasher};
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::sleep;
@@ -43,6 +45,39 @@ impl Deref for Watchdog {
     }
 }
 
+/// A number in [0, 1) from the std hasher's random seed, good enough for jitter.
+pub fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Given each node's outgoing edges, returns a node on a cycle, if there is one.
+pub fn find_cycle(edges: &[Vec<usize>]) -> Option<usize> {
+    // Depth-first search; a node met again while still on the path closes a cycle
+    fn visit(
+        idx: usize,
+        edges: &[Vec<usize>],
+        path: &mut Vec<usize>,
+        done: &mut HashSet<usize>,
+    ) -> Result<(), usize> {
+        if done.contains(&idx) {
+            return Ok(());
+        }
+        if path.contains(&idx) {
+            return Err(idx);
+        }
+        path.push(idx);
+        for next in &edges[idx] {
+            visit(*next, edges, path, done)?;
+        }
+        path.pop();
+        done.insert(idx);
+        Ok(())
+    }
+    let mut done = HashSet::new();
+    (0..edges.len()).find_map(|idx| visit(idx, edges, &mut Vec::new(), &mut done).err())
+}
+
 #[cfg(test)]
 mod tests {
     use std::thread::scope;
@@ -66,4 +101,12 @@ mod tests {
         });
         assert!(Instant::now() < deadline);
     }
+
+    #[test]
+    fn find_cycles() {
+        assert_eq!(super::find_cycle(&[vec![1], vec![2], vec![]]), None);
+        assert_eq!(super::find_cycle(&[vec![1, 2], vec![2], vec![]]), None);
+        assert!(super::find_cycle(&[vec![], vec![2], vec![1]]).is_some());
+        assert_eq!(super::find_cycle(&[vec![0]]), Some(0));
+    }
 }
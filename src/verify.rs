@@ -0,0 +1,270 @@
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use gix::ObjectId;
+
+use crate::{
+    config::VerifyConfig,
+    errors::GitOpsError,
+    gix::{commit_signatures, CommitSignature},
+};
+
+const SSH_SIGNATURE: &[u8] = b"-----BEGIN SSH SIGNATURE-----";
+
+/// Check that `new_sha`, or with `all_commits` every commit since
+/// `current_sha`, is signed by an allowed key.
+pub fn verify_commits<P>(
+    config: &VerifyConfig,
+    repodir: P,
+    current_sha: ObjectId,
+    new_sha: ObjectId,
+) -> Result<(), GitOpsError>
+where
+    P: AsRef<Path>,
+{
+    // Without a deployed commit there is nothing to stop the walk
+    let from = Some(current_sha).filter(|sha| config.all_commits && !sha.is_null());
+    for (sha, signature) in commit_signatures(repodir, from, new_sha)? {
+        let signature =
+            signature.ok_or_else(|| GitOpsError::UnverifiedCommit(sha, "not signed".to_owned()))?;
+        verify_signature(config, &signature)
+            .map_err(|reason| GitOpsError::UnverifiedCommit(sha, reason))?;
+    }
+    Ok(())
+}
+
+fn verify_signature(config: &VerifyConfig, signed: &CommitSignature) -> Result<(), String> {
+    let dir = tempfile::tempdir().map_err(|err| err.to_string())?;
+    let signature_file = dir.path().join("signature");
+    std::fs::write(&signature_file, &signed.signature).map_err(|err| err.to_string())?;
+    if signed.signature.starts_with(SSH_SIGNATURE) {
+        let allowed_signers = config
+            .allowed_signers
+            .as_ref()
+            .ok_or("SSH signature but no allowed_signers configured")?;
+        let principals = run(
+            Command::new("ssh-keygen")
+                .args(["-Y", "find-principals", "-f"])
+                .arg(allowed_signers)
+                .arg("-s")
+                .arg(&signature_file),
+            &[],
+        )?;
+        let principal = principals
+            .lines()
+            .next()
+            .ok_or("signing key not in allowed_signers")?;
+        run(
+            Command::new("ssh-keygen")
+                .args(["-Y", "verify", "-n", "git", "-f"])
+                .arg(allowed_signers)
+                .args(["-I", principal, "-s"])
+                .arg(&signature_file),
+            &signed.payload,
+        )?;
+    } else {
+        let keyring = config
+            .gpg_keyring
+            .as_ref()
+            .ok_or("GPG signature but no gpg_keyring configured")?;
+        // A home of our own keeps gpg from consulting the user's keys and trust
+        let status = run(
+            Command::new("gpg")
+                .arg("--homedir")
+                .arg(dir.path())
+                .args(["--batch", "--no-default-keyring", "--keyring"])
+                .arg(keyring)
+                .args(["--status-fd", "1", "--verify"])
+                .arg(&signature_file)
+                .arg("-"),
+            &signed.payload,
+        )?;
+        let fingerprints = good_signature_fingerprints(&status)?;
+        // Signing subkey and, last, the primary key
+        let allowed = config.gpg_fingerprints.is_empty()
+            || config.gpg_fingerprints.iter().any(|allowed| {
+                [fingerprints.first(), fingerprints.last()]
+                    .into_iter()
+                    .flatten()
+                    .any(|fpr| fpr.eq_ignore_ascii_case(allowed))
+            });
+        if !allowed {
+            return Err(format!(
+                "signed by {} which is not in gpg_fingerprints",
+                fingerprints[0]
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fingerprints from gpg's status output, provided the signature is good.
+/// gpg reports VALIDSIG for signatures by expired or revoked keys too, and
+/// may still exit 0, so like git this requires GOODSIG and refuses the rest.
+fn good_signature_fingerprints(status: &str) -> Result<Vec<&str>, String> {
+    let mut good = false;
+    let mut fingerprints = None;
+    for line in status.lines() {
+        let Some(line) = line.strip_prefix("[GNUPG:] ") else {
+            continue;
+        };
+        let (keyword, args) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "GOODSIG" => good = true,
+            "VALIDSIG" => fingerprints = Some(args.split(' ').collect::<Vec<_>>()),
+            "BADSIG" => return Err("bad signature".to_owned()),
+            "EXPSIG" => return Err("expired signature".to_owned()),
+            "EXPKEYSIG" => return Err("signed by an expired key".to_owned()),
+            "REVKEYSIG" => return Err("signed by a revoked key".to_owned()),
+            _ => (),
+        }
+    }
+    match fingerprints {
+        Some(fingerprints) if good => Ok(fingerprints),
+        _ => Err("no valid signature".to_owned()),
+    }
+}
+
+/// Run a verification tool, returning its stdout or why it failed.
+fn run(command: &mut Command, stdin: &[u8]) -> Result<String, String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("{}: {}", program, err))?;
+    // Written before waiting; payloads are small commit objects
+    let written = child.stdin.take().unwrap().write_all(stdin);
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("{}: {}", program, err))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{}: {}", program, stderr.trim()));
+    }
+    written.map_err(|err| format!("{}: {}", program, err))?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use crate::{config::VerifyConfig, gix::CommitSignature};
+
+    use super::{good_signature_fingerprints, verify_signature};
+
+    fn ssh_signed(dir: &std::path::Path, payload: &[u8]) -> CommitSignature {
+        let key = dir.join("key");
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "ze-signer", "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let public = std::fs::read_to_string(dir.join("key.pub")).unwrap();
+        std::fs::write(
+            dir.join("allowed_signers"),
+            format!("ze-signer@example.com {}", public),
+        )
+        .unwrap();
+        std::fs::write(dir.join("payload"), payload).unwrap();
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-Y", "sign", "-n", "git", "-f"])
+            .arg(&key)
+            .arg(dir.join("payload"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        CommitSignature {
+            signature: std::fs::read(dir.join("payload.sig")).unwrap(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs ssh-keygen"]
+    fn verify_ssh_signature() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut signed = ssh_signed(dir.path(), b"ze commit\n");
+        let config = VerifyConfig {
+            allowed_signers: Some(dir.path().join("allowed_signers")),
+            ..Default::default()
+        };
+        assert_eq!(verify_signature(&config, &signed), Ok(()));
+        signed.payload = b"ze tampered commit\n".to_vec();
+        assert!(verify_signature(&config, &signed).is_err());
+    }
+
+    const VALIDSIG: &str =
+        "[GNUPG:] VALIDSIG 0123SUBKEY 2024-01-01 1704067200 0 4 0 22 10 00 4567PRIMARY";
+
+    #[test]
+    fn accept_good_gpg_signature() {
+        let status = format!(
+            "[GNUPG:] NEWSIG\n[GNUPG:] KEY_CONSIDERED 4567PRIMARY 0\n\
+             [GNUPG:] SIG_ID ze-sig 2024-01-01 1704067200\n\
+             [GNUPG:] GOODSIG 89ABKEYID ze-signer <ze-signer@example.com>\n{}\n\
+             [GNUPG:] TRUST_UNDEFINED 0 pgp\n",
+            VALIDSIG
+        );
+        assert_eq!(
+            good_signature_fingerprints(&status),
+            Ok(vec![
+                "0123SUBKEY",
+                "2024-01-01",
+                "1704067200",
+                "0",
+                "4",
+                "0",
+                "22",
+                "10",
+                "00",
+                "4567PRIMARY"
+            ])
+        );
+    }
+
+    #[test]
+    fn refuse_gpg_signature_by_expired_or_revoked_key() {
+        for (keyword, reason) in [
+            ("EXPKEYSIG", "signed by an expired key"),
+            ("REVKEYSIG", "signed by a revoked key"),
+            ("EXPSIG", "expired signature"),
+        ] {
+            // gpg still reports VALIDSIG for these
+            let status = format!(
+                "[GNUPG:] NEWSIG\n[GNUPG:] {} 89ABKEYID ze-signer <ze-signer@example.com>\n{}\n",
+                keyword, VALIDSIG
+            );
+            assert_eq!(good_signature_fingerprints(&status), Err(reason.to_owned()));
+        }
+        let status =
+            "[GNUPG:] NEWSIG\n[GNUPG:] BADSIG 89ABKEYID ze-signer <ze-signer@example.com>\n";
+        assert_eq!(
+            good_signature_fingerprints(status),
+            Err("bad signature".to_owned())
+        );
+        assert_eq!(
+            good_signature_fingerprints(VALIDSIG),
+            Err("no valid signature".to_owned())
+        );
+    }
+
+    #[test]
+    fn refuse_signature_without_trusted_keys() {
+        let signed = CommitSignature {
+            signature: b"-----BEGIN PGP SIGNATURE-----\n".to_vec(),
+            payload: b"ze commit\n".to_vec(),
+        };
+        let res = verify_signature(&VerifyConfig::default(), &signed);
+        assert_eq!(
+            res,
+            Err("GPG signature but no gpg_keyring configured".to_owned())
+        );
+    }
+}
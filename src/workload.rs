@@ -1,23 +1,57 @@
 use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    panic::{catch_unwind, AssertUnwindSafe},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+        Arc, Mutex,
+    },
+    thread::scope,
+    time::{Instant, SystemTime},
 };
 
 use gix::ObjectId;
 
 use crate::{
-    actions::{run_action, Action, ActionResult},
-    config::GitTaskConfig,
+    actions::{resolve_secrets, run_action, Action, ActionResult},
+    config::{FailurePolicy, GitTaskConfig, NonFastForwardPolicy, RetryConfig},
     errors::GitOpsError,
-    gix::{ensure_worktree, UrlProvider},
+    gix::{changed_paths, checkout_sha, ensure_worktree, is_ancestor, UrlProvider},
     receiver::WorkloadEvent,
+    sops::decrypt_worktree,
+    state::State,
+    verify::verify_commits,
 };
 
+/// What a run that did not fail came to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunOutcome {
+    /// The task is at this commit, whether deployed now or already before
+    Deployed(ObjectId),
+    /// A new commit is pending but was not deployed, e.g. because it was
+    /// given up on or is waiting for its deploy window
+    Skipped,
+}
+
 pub trait Workload {
     fn id(&self) -> String;
-    fn interval(&self) -> Duration;
-    fn perform(self, workdir: PathBuf, current_sha: ObjectId) -> Result<ObjectId, GitOpsError>;
+    /// Tasks with the same repo id share a local clone and must not run concurrently.
+    fn repo_id(&self) -> String;
+    /// When to run next if the current run started at the given time.
+    fn next_run(&self, now: SystemTime) -> SystemTime;
+    fn retry(&self) -> RetryConfig;
+    /// Ids of tasks that must settle successfully before this task may run.
+    fn depends_on(&self) -> Vec<String>;
+    /// Only deploy this commit, or anything if None. Set before each run.
+    fn require_sha(&mut self, sha: Option<ObjectId>);
+    /// Abandon the run once the flag is raised, e.g. when another instance
+    /// has taken over the task. Set before each run.
+    fn set_interrupt(&mut self, interrupted: Arc<AtomicBool>);
+    /// Report something that happened outside a run, e.g. losing the task's lease.
+    fn notify(&self, event: WorkloadEvent);
+    fn perform(self, workdir: PathBuf, state: State) -> Result<RunOutcome, GitOpsError>;
 }
 
 #[allow(clippy::type_complexity)]
@@ -27,6 +61,12 @@ pub struct GitWorkload {
     config: GitTaskConfig,
     url_provider: Arc<Box<dyn UrlProvider>>,
     repo_dir: PathBuf,
+    required_sha: Option<ObjectId>,
+    /// Set during a run when actions have path conditions
+    changed_paths: Option<Vec<String>>,
+    secret_env_allow: HashSet<String>,
+    shutdown: Arc<AtomicBool>,
+    interrupted: Arc<AtomicBool>,
     watchers:
         Vec<Arc<Mutex<Box<dyn Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static>>>>,
 }
@@ -38,6 +78,8 @@ impl GitWorkload {
         repo_dir: &Path,
     ) -> Self {
         let repo_dir = repo_dir.join(url_provider.safe_url());
+        let mut config = config;
+        hide_from_sandboxes(&mut config, &repo_dir);
         let actions = config
             .actions
             .iter()
@@ -48,10 +90,29 @@ impl GitWorkload {
             config,
             url_provider: Arc::new(Box::new(url_provider)),
             repo_dir,
+            required_sha: None,
+            changed_paths: None,
+            secret_env_allow: HashSet::new(),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            interrupted: Arc::new(AtomicBool::new(false)),
             watchers: Vec::new(),
         }
     }
 
+    /// Once the flag is raised, running actions are terminated and no new ones start.
+    pub fn set_shutdown(&mut self, shutdown: Arc<AtomicBool>) {
+        self.shutdown = shutdown;
+    }
+
+    fn stopping(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed) || self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// Variables in our environment that actions may receive through secret_env.
+    pub fn allow_secret_env(&mut self, vars: HashSet<String>) {
+        self.secret_env_allow = vars;
+    }
+
     pub fn watch(
         &mut self,
         watcher: impl Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static,
@@ -61,86 +122,473 @@ impl GitWorkload {
 
     fn run_actions(
         &self,
+        actions: &[Action],
         workdir: &Path,
         deadline: Instant,
         sink: &Arc<Mutex<impl Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static>>,
     ) -> Result<Option<String>, GitOpsError> {
-        for action in &self.actions {
-            let name = format!("{}|{}", self.config.name, action.id());
-            let res = run_action(&name, action, workdir, deadline, sink)?;
-            if res != ActionResult::Success {
-                return Ok(Some(name));
+        let parallelism = self.config.parallelism.max(1);
+        // Names of actions that dependents may proceed after
+        let mut finished = HashSet::new();
+        let mut started = vec![false; actions.len()];
+        let mut failed = None;
+        let mut error = None;
+        let (tx, rx) = channel();
+        scope(|s| {
+            let mut running = 0;
+            loop {
+                for (idx, action) in actions.iter().enumerate() {
+                    if running >= parallelism || failed.is_some() || error.is_some() {
+                        break;
+                    }
+                    let ready = action.config().needs.iter().all(|n| finished.contains(n));
+                    if started[idx] || !ready {
+                        continue;
+                    }
+                    let name = format!("{}|{}", self.config.name, action.id());
+                    if self.stopping() {
+                        error = Some(GitOpsError::ActionInterrupted(name));
+                        break;
+                    }
+                    started[idx] = true;
+                    running += 1;
+                    let tx = tx.clone();
+                    s.spawn(move || {
+                        // A panicking action must still be accounted for, or we wait forever
+                        let res = catch_unwind(AssertUnwindSafe(|| {
+                            self.run_single_action(&name, action, workdir, deadline, sink)
+                        }))
+                        .unwrap_or_else(|_| Err(GitOpsError::WorkerPanic(name.clone())));
+                        tx.send((idx, name, res)).unwrap();
+                    });
+                }
+                if running == 0 {
+                    break;
+                }
+                let (idx, name, res) = rx.recv().unwrap();
+                running -= 1;
+                match res {
+                    Ok(ActionResult::Failure) if !actions[idx].config().continue_on_error => {
+                        failed.get_or_insert(name);
+                    }
+                    Ok(_) => {
+                        finished.insert(actions[idx].id());
+                    }
+                    Err(err) => {
+                        error.get_or_insert(err);
+                    }
+                }
             }
+        });
+        match error {
+            Some(err) => Err(err),
+            None => Ok(failed),
         }
-        Ok(None)
     }
-}
 
-impl Workload for GitWorkload {
-    fn id(&self) -> String {
-        self.config.name.clone()
+    /// Run an action, subject to its when condition and retries.
+    fn run_single_action(
+        &self,
+        name: &str,
+        action: &Action,
+        workdir: &Path,
+        deadline: Instant,
+        sink: &Arc<Mutex<impl Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static>>,
+    ) -> Result<ActionResult, GitOpsError> {
+        let config = action.config();
+        let run = config.when.as_ref().map_or(true, |when| {
+            when.matches(&self.config.git.branch, self.changed_paths.as_deref())
+        });
+        if !run {
+            sink.lock().unwrap()(WorkloadEvent::ActionFinished(
+                name.to_owned(),
+                ActionResult::Skipped,
+                0,
+            ))
+            .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+            return Ok(ActionResult::Skipped);
+        }
+        let mut action = action.clone();
+        action.set_secrets(resolve_secrets(&config.secret_env, &self.secret_env_allow)?);
+        let mut attempts = 0;
+        let res = loop {
+            attempts += 1;
+            let action_deadline = config
+                .timeout
+                .map_or(deadline, |timeout| deadline.min(Instant::now() + timeout));
+            let res = run_action(
+                name,
+                &action,
+                workdir,
+                action_deadline,
+                &|| self.stopping(),
+                sink,
+            )?;
+            if res == ActionResult::Success
+                || attempts > config.retries
+                || self.stopping()
+                || Instant::now() > deadline
+            {
+                break res;
+            }
+        };
+        sink.lock().unwrap()(WorkloadEvent::ActionFinished(
+            name.to_owned(),
+            res,
+            attempts,
+        ))
+        .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+        Ok(res)
     }
 
-    fn interval(&self) -> Duration {
-        self.config.interval
+    fn has_path_conditions(&self) -> bool {
+        self.config
+            .actions
+            .iter()
+            .chain(&self.config.on_success)
+            .chain(&self.config.on_failure)
+            .chain(&self.config.always)
+            .any(|action| {
+                action
+                    .when
+                    .as_ref()
+                    .is_some_and(|when| !when.paths.is_empty())
+            })
     }
 
-    fn perform(mut self, workdir: PathBuf, current_sha: ObjectId) -> Result<ObjectId, GitOpsError> {
+    /// Re-run the actions for the last successful commit in a fresh workdir,
+    /// reporting the outcome through events rather than as an error. Returns
+    /// whether the rollback succeeded.
+    fn rollback(
+        &self,
+        workdir: &Path,
+        failed_sha: ObjectId,
+        target_sha: ObjectId,
+        sink: &Arc<Mutex<impl Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static>>,
+    ) -> Result<bool, GitOpsError> {
+        sink.lock().unwrap()(WorkloadEvent::RollingBack(
+            self.config.name.clone(),
+            failed_sha,
+            target_sha,
+        ))
+        .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+        let reason = match self.run_rollback(workdir, failed_sha, target_sha, sink) {
+            Ok(None) => None,
+            Ok(Some(action_name)) => Some(format!("action {} failed", action_name)),
+            Err(err) => Some(format!("{}", err)),
+        };
+        let rolled_back = reason.is_none();
+        sink.lock().unwrap()(WorkloadEvent::RolledBack(
+            self.config.name.clone(),
+            failed_sha,
+            target_sha,
+            reason,
+        ))
+        .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+        Ok(rolled_back)
+    }
+
+    fn run_rollback(
+        &self,
+        workdir: &Path,
+        failed_sha: ObjectId,
+        target_sha: ObjectId,
+        sink: &Arc<Mutex<impl Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static>>,
+    ) -> Result<Option<String>, GitOpsError> {
+        std::fs::remove_dir_all(workdir).map_err(GitOpsError::WorkDir)?;
+        std::fs::create_dir_all(workdir).map_err(GitOpsError::WorkDir)?;
+        checkout_sha(&self.repo_dir, target_sha, workdir)?;
+        if let Some(decrypt) = &self.config.decrypt {
+            decrypt_worktree(decrypt, workdir)?;
+        }
+        let mut actions = self.actions.clone();
+        actions.iter_mut().for_each(|action| {
+            action.set_env("KITOPS_SHA".to_string(), target_sha.to_string());
+            action.set_env("KITOPS_FAILED_SHA".to_string(), failed_sha.to_string());
+            action.set_env("KITOPS_ROLLBACK".to_string(), "1".to_string());
+        });
+        // The failed run may have used up the deadline
+        let deadline = Instant::now() + self.config.timeout;
+        self.run_actions(&actions, workdir, deadline, sink)
+    }
+
+    fn should_roll_back(&self, current_sha: ObjectId) -> bool {
+        self.config.failure_policy == FailurePolicy::Rollback
+            && !current_sha.is_null()
+            && !self.stopping()
+    }
+
+    /// Run on_success or on_failure hooks, then always hooks. Hooks report
+    /// through events like other actions but do not change the run's outcome.
+    fn run_hooks(
+        &self,
+        result: &Result<ObjectId, GitOpsError>,
+        env: &[(String, String)],
+        workdir: &Path,
+        sink: &Arc<Mutex<impl Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static>>,
+    ) -> Result<(), GitOpsError> {
+        let (outcome, failed_action, hooks) = match result {
+            Ok(_) => ("success", None, &self.config.on_success[..]),
+            Err(GitOpsError::ActionFailed(_, action_name, _)) => (
+                "failure",
+                Some(action_name.clone()),
+                &self.config.on_failure[..],
+            ),
+            Err(_) => ("error", None, &self.config.on_failure[..]),
+        };
+        for hooks in [hooks, &self.config.always[..]] {
+            let actions = hooks
+                .iter()
+                .map(|config| {
+                    let mut action = Action::new(config.clone());
+                    for (key, val) in env {
+                        action.set_env(key.clone(), val.clone());
+                    }
+                    action.set_env("KITOPS_RESULT".to_string(), outcome.to_string());
+                    if let Some(ref failed_action) = failed_action {
+                        action.set_env("KITOPS_FAILED_ACTION".to_string(), failed_action.clone());
+                    }
+                    action
+                })
+                .collect::<Vec<_>>();
+            // Hooks should get to run even when the actions ran out of time
+            let deadline = Instant::now() + self.config.timeout;
+            let reason = match self.run_actions(&actions, workdir, deadline, sink) {
+                Ok(None) => continue,
+                Ok(Some(action_name)) => format!("action {} failed", action_name),
+                Err(err) => format!("{}", err),
+            };
+            sink.lock().unwrap()(WorkloadEvent::HookFailed(self.config.name.clone(), reason))
+                .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+        }
+        Ok(())
+    }
+
+    fn perform_in(
+        &mut self,
+        workdir: &Path,
+        state: &State,
+        sink: &Arc<Mutex<impl Fn(WorkloadEvent) -> Result<(), GitOpsError> + Send + 'static>>,
+    ) -> Result<RunOutcome, GitOpsError> {
+        let current_sha = state.current_sha;
         let deadline = Instant::now() + self.config.timeout;
-        let watchers = self.watchers.clone();
-        let sink = Arc::new(Mutex::new(move |event: WorkloadEvent| {
-            for watcher in &watchers {
-                watcher.lock().unwrap()(event.clone())?;
-            }
-            Ok::<_, GitOpsError>(())
-        }));
-        let url = self.url_provider.auth_url()?;
         let branch = self.config.git.branch.clone();
-        let new_sha = ensure_worktree(url, &branch, deadline, &self.repo_dir, &workdir)?;
-        if current_sha != new_sha {
-            self.actions.iter_mut().for_each(|action| {
-                action.set_env(
-                    "KITOPS_LAST_SUCCESSFUL_SHA".to_string(),
-                    current_sha.to_string(),
-                );
-                action.set_env("KITOPS_SHA".to_string(), new_sha.to_string());
-            });
-            sink.lock().unwrap()(WorkloadEvent::Changes(
+        let new_sha = match self
+            .url_provider
+            .auth_url()
+            .and_then(|url| ensure_worktree(url, &branch, deadline, &self.repo_dir, workdir))
+        {
+            Ok(new_sha) => new_sha,
+            Err(err) => {
+                sink.lock().unwrap()(WorkloadEvent::FetchFailed(
+                    self.config.name.clone(),
+                    format!("{}", err),
+                ))
+                .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+                return Err(err);
+            }
+        };
+        if current_sha == new_sha {
+            return Ok(RunOutcome::Deployed(current_sha));
+        }
+        if state.given_up_sha == Some(new_sha) {
+            return Ok(RunOutcome::Skipped);
+        }
+        let policy = self.config.git.non_fast_forward;
+        if policy != NonFastForwardPolicy::Allow
+            && !current_sha.is_null()
+            && !is_ancestor(&self.repo_dir, current_sha, new_sha)?
+        {
+            let refused = policy == NonFastForwardPolicy::Refuse;
+            sink.lock().unwrap()(WorkloadEvent::NonFastForward(
                 self.config.name.clone(),
                 current_sha,
                 new_sha,
+                refused,
             ))
             .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
-            // TODO The returns dodge cleanup
-            match self.run_actions(&workdir, deadline, &sink) {
-                Ok(None) => {
-                    sink.lock().unwrap()(WorkloadEvent::Success(self.config.name.clone(), new_sha))
-                        .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?
-                }
-                Ok(Some(action_name)) => {
-                    sink.lock().unwrap()(WorkloadEvent::Failure(
+            if refused {
+                return Ok(RunOutcome::Skipped);
+            }
+        }
+        if self.required_sha.is_some_and(|sha| sha != new_sha) {
+            sink.lock().unwrap()(WorkloadEvent::AwaitingDependencies(
+                self.config.name.clone(),
+                new_sha,
+            ))
+            .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+            return Ok(RunOutcome::Skipped);
+        }
+        if !self.config.in_deploy_window(SystemTime::now()) {
+            sink.lock().unwrap()(WorkloadEvent::Deferred(self.config.name.clone(), new_sha))
+                .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+            return Ok(RunOutcome::Skipped);
+        }
+        if !current_sha.is_null() && self.has_path_conditions() {
+            // E.g. the last deployed commit was force-pushed away; run all actions then
+            self.changed_paths = changed_paths(&self.repo_dir, current_sha, new_sha).ok();
+        }
+        if let Some(verify) = &self.config.verify {
+            if let Err(err) = verify_commits(verify, &self.repo_dir, current_sha, new_sha) {
+                if let GitOpsError::UnverifiedCommit(sha, reason) = &err {
+                    sink.lock().unwrap()(WorkloadEvent::Unverified(
                         self.config.name.clone(),
-                        action_name.clone(),
-                        new_sha,
+                        *sha,
+                        reason.clone(),
                     ))
                     .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
-                    return Err(GitOpsError::ActionFailed(
-                        self.config.name.clone(),
-                        action_name,
-                    ));
                 }
-                Err(err) => {
-                    sink.lock().unwrap()(WorkloadEvent::Error(
-                        self.config.name.clone(),
-                        format!("{}", err),
-                        new_sha,
-                    ))
+                return Err(match err {
+                    // The head is what gets given up on, and it cannot pass while an ancestor fails
+                    GitOpsError::UnverifiedCommit(sha, reason) if sha != new_sha => {
+                        GitOpsError::UnverifiedCommit(
+                            new_sha,
+                            format!("ancestor {} failed verification: {}", sha, reason),
+                        )
+                    }
+                    err => err,
+                });
+            }
+        }
+        if let Some(decrypt) = &self.config.decrypt {
+            decrypt_worktree(decrypt, workdir)?;
+        }
+        let env = [
+            ("KITOPS_LAST_SUCCESSFUL_SHA", current_sha.to_string()),
+            ("KITOPS_SHA", new_sha.to_string()),
+            ("KITOPS_ATTEMPT", (state.failed_attempts + 1).to_string()),
+        ]
+        .map(|(key, val)| (key.to_string(), val));
+        self.actions.iter_mut().for_each(|action| {
+            for (key, val) in &env {
+                action.set_env(key.clone(), val.clone());
+            }
+        });
+        sink.lock().unwrap()(WorkloadEvent::Changes(
+            self.config.name.clone(),
+            current_sha,
+            new_sha,
+        ))
+        .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+        let result = match self.run_actions(&self.actions, workdir, deadline, sink) {
+            Ok(None) => {
+                sink.lock().unwrap()(WorkloadEvent::Success(self.config.name.clone(), new_sha))
                     .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
-                    return Err(err);
-                }
+                Ok(new_sha)
+            }
+            Ok(Some(action_name)) => {
+                sink.lock().unwrap()(WorkloadEvent::Failure(
+                    self.config.name.clone(),
+                    action_name.clone(),
+                    new_sha,
+                ))
+                .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+                Err(GitOpsError::ActionFailed(
+                    self.config.name.clone(),
+                    action_name,
+                    new_sha,
+                ))
+            }
+            Err(err) => {
+                sink.lock().unwrap()(WorkloadEvent::Error(
+                    self.config.name.clone(),
+                    format!("{}", err),
+                    new_sha,
+                ))
+                .map_err(|err| GitOpsError::NotifyError(format!("{}", err)))?;
+                Err(err)
+            }
+        };
+        // Hooks see the checkout that KITOPS_SHA names, so any rollback comes after them
+        self.run_hooks(&result, &env, workdir, sink)?;
+        if result.is_err()
+            && self.should_roll_back(current_sha)
+            && self.rollback(workdir, new_sha, current_sha, sink)?
+        {
+            return result
+                .map(RunOutcome::Deployed)
+                .map_err(|err| GitOpsError::RolledBack(new_sha, Box::new(err)));
+        }
+        result.map(RunOutcome::Deployed)
+    }
+}
+
+/// Keep the repo cache and the task's keys out of reach of sandboxed actions.
+fn hide_from_sandboxes(config: &mut GitTaskConfig, repo_dir: &Path) {
+    let paths = [
+        Some(repo_dir.to_owned()),
+        config
+            .decrypt
+            .as_ref()
+            .map(|decrypt| decrypt.age_identity.clone()),
+        config
+            .github
+            .as_ref()
+            .map(|github| github.private_key_file.clone()),
+    ];
+    let sandboxes = config
+        .actions
+        .iter_mut()
+        .chain(config.on_success.iter_mut())
+        .chain(config.on_failure.iter_mut())
+        .chain(config.always.iter_mut())
+        .filter_map(|action| action.sandbox.as_mut());
+    for sandbox in sandboxes {
+        sandbox.hide.extend(paths.iter().flatten().cloned());
+    }
+}
+
+impl Workload for GitWorkload {
+    fn id(&self) -> String {
+        self.config.name.clone()
+    }
+
+    fn repo_id(&self) -> String {
+        self.repo_dir.to_string_lossy().into_owned()
+    }
+
+    fn next_run(&self, now: SystemTime) -> SystemTime {
+        self.config.next_run(now)
+    }
+
+    fn retry(&self) -> RetryConfig {
+        self.config.retry.clone()
+    }
+
+    fn depends_on(&self) -> Vec<String> {
+        self.config.depends_on.clone()
+    }
+
+    fn require_sha(&mut self, sha: Option<ObjectId>) {
+        self.required_sha = sha;
+    }
+
+    fn set_interrupt(&mut self, interrupted: Arc<AtomicBool>) {
+        self.interrupted = interrupted;
+    }
+
+    fn notify(&self, event: WorkloadEvent) {
+        // There is no run for a failing watcher to fail
+        for watcher in &self.watchers {
+            let _ = watcher.lock().unwrap()(event.clone());
+        }
+    }
+
+    fn perform(mut self, workdir: PathBuf, state: State) -> Result<RunOutcome, GitOpsError> {
+        let watchers = self.watchers.clone();
+        let sink = Arc::new(Mutex::new(move |event: WorkloadEvent| {
+            for watcher in &watchers {
+                watcher.lock().unwrap()(event.clone())?;
+            }
+            Ok::<_, GitOpsError>(())
+        }));
+        let result = self.perform_in(&workdir, &state, &sink);
+        // Failed runs leave the workdir behind too; an earlier error takes precedence
+        match std::fs::remove_dir_all(&workdir) {
+            Err(err) if err.kind() != ErrorKind::NotFound && result.is_ok() => {
+                Err(GitOpsError::WorkDir(err))
             }
+            _ => result,
         }
-        std::fs::remove_dir_all(&workdir).map_err(GitOpsError::WorkDir)?;
-        Ok(new_sha)
     }
 }
@@ -2,7 +2,12 @@ use xshell::cmd;
 
 use std::time::{Duration, Instant};
 
-use kitops::{config::GitConfig, gix::ensure_worktree};
+use gix::ObjectId;
+use kitops::{
+    config::GitConfig,
+    errors::GitOpsError,
+    gix::{commit_signatures, ensure_worktree, is_ancestor},
+};
 
 use utils::{clone_repo, commit_file, empty_repo, reset_branch, shell, TEST_CONFIG};
 
@@ -82,3 +87,65 @@ fn fetch_repo_with_force_push() {
         "revision 1"
     );
 }
+
+#[test]
+fn clone_repo_with_missing_branch() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let config =
+        serde_yaml::from_str::<GitConfig>(&format!("url: file://{}", upstream.path().display()))
+            .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let workdir = tempfile::tempdir().unwrap();
+    let res = ensure_worktree(config.url, "no-such-branch", deadline, &repodir, &workdir);
+    assert!(matches!(res, Err(GitOpsError::MissingBranch(_))));
+}
+
+#[test]
+fn commit_signatures_skip_history_deployed_before_merge() {
+    let sh = shell();
+    let repo = empty_repo(&sh);
+    commit_file(&repo, "base");
+    sh.change_dir(&repo);
+    cmd!(sh, "git checkout -q -b side").run().unwrap();
+    let side = commit_file(&repo, "side");
+    cmd!(sh, "git checkout -q main").run().unwrap();
+    let deployed = commit_file(&repo, "deployed");
+    cmd!(sh, "git -c user.email=testing@example.com -c user.name=Testing merge -q --no-ff -s ours -m 'Merging side' side")
+        .run()
+        .unwrap();
+    let merge = cmd!(sh, "git rev-parse HEAD").read().unwrap();
+    let parse = |sha: &str| ObjectId::from_hex(sha.as_bytes()).unwrap();
+    let signatures = commit_signatures(&repo, Some(parse(&deployed)), parse(&merge)).unwrap();
+    let mut commits = signatures
+        .into_iter()
+        .map(|(oid, signature)| {
+            assert!(signature.is_none());
+            oid.to_string()
+        })
+        .collect::<Vec<_>>();
+    commits.sort();
+    let mut expected = vec![merge, side];
+    expected.sort();
+    assert_eq!(commits, expected);
+}
+
+#[test]
+fn history_beyond_shallow_boundary_is_not_ancestor() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let first = commit_file(&upstream, "revision 1");
+    commit_file(&upstream, "revision 2");
+    let head = commit_file(&upstream, "revision 3");
+    let repodir = tempfile::tempdir().unwrap();
+    let ref psource = format!("file://{}", upstream.path().display());
+    let ref prepodir = repodir.path();
+    cmd!(sh, "git clone -q --depth 1 {psource} {prepodir}")
+        .run()
+        .unwrap();
+    let parse = |sha: &str| ObjectId::from_hex(sha.as_bytes()).unwrap();
+    assert!(!is_ancestor(&repodir, parse(&first), parse(&head)).unwrap());
+    assert!(is_ancestor(&repodir, parse(&head), parse(&head)).unwrap());
+}
@@ -1,12 +1,15 @@
 use std::sync::{Arc, Mutex};
 
+use chrono::{Datelike, Utc};
 use gix::{hash::Kind, ObjectId};
 use kitops::{
-    config::GitTaskConfig,
+    actions::ActionResult,
+    config::{FailurePolicy, GitTaskConfig, NonFastForwardPolicy, VerifyConfig},
     errors::GitOpsError,
     gix::DefaultUrlProvider,
     receiver::{SourceType, WorkloadEvent},
-    workload::{GitWorkload, Workload},
+    state::State,
+    workload::{GitWorkload, RunOutcome, Workload},
 };
 use utils::*;
 
@@ -30,6 +33,13 @@ actions:
     .unwrap()
 }
 
+fn state(current_sha: ObjectId) -> State {
+    State {
+        current_sha,
+        ..Default::default()
+    }
+}
+
 fn non_action_events(events: Arc<Mutex<Vec<WorkloadEvent>>>) -> Vec<WorkloadEvent> {
     events
         .lock()
@@ -38,7 +48,9 @@ fn non_action_events(events: Arc<Mutex<Vec<WorkloadEvent>>>) -> Vec<WorkloadEven
         .filter(|e| {
             !matches!(
                 e,
-                WorkloadEvent::ActionOutput(..) | WorkloadEvent::ActionExit(..)
+                WorkloadEvent::ActionOutput(..)
+                    | WorkloadEvent::ActionExit(..)
+                    | WorkloadEvent::ActionFinished(..)
             )
         })
         .cloned()
@@ -64,7 +76,9 @@ fn watch_successful_workload() {
         Ok(())
     });
     let prev_sha = ObjectId::empty_tree(Kind::Sha1);
-    workload.perform(workdir.into_path(), prev_sha).unwrap();
+    workload
+        .perform(workdir.into_path(), state(prev_sha))
+        .unwrap();
     assert_eq!(
         non_action_events(events),
         vec![
@@ -92,7 +106,7 @@ fn watch_failing_workload() {
         Ok(())
     });
     let prev_sha = ObjectId::empty_tree(Kind::Sha1);
-    let res = workload.perform(workdir.into_path(), prev_sha);
+    let res = workload.perform(workdir.into_path(), state(prev_sha));
     assert!(matches!(res, Err(GitOpsError::ActionFailed(..))));
     let events = non_action_events(events);
     assert_eq!(events.len(), 2);
@@ -118,7 +132,7 @@ fn watch_erroring_workload() {
         Ok(())
     });
     let prev_sha = ObjectId::empty_tree(Kind::Sha1);
-    let res = workload.perform(workdir.into_path(), prev_sha);
+    let res = workload.perform(workdir.into_path(), state(prev_sha));
     assert!(matches!(res, Err(GitOpsError::ActionError(..))));
     let events = non_action_events(events);
     assert_eq!(events.len(), 2);
@@ -145,7 +159,9 @@ fn woarkload_gets_sha_env() {
         Ok(())
     });
     let prev_sha = ObjectId::empty_tree(Kind::Sha1);
-    workload.perform(workdir.into_path(), prev_sha).unwrap();
+    workload
+        .perform(workdir.into_path(), state(prev_sha))
+        .unwrap();
     assert_eq!(
         events
             .lock()
@@ -159,3 +175,524 @@ fn woarkload_gets_sha_env() {
         ))
     );
 }
+
+#[cfg(unix)]
+#[test]
+fn workload_gets_attempt_env() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let config = config(&upstream, "/bin/sh", &["-c", "echo $KITOPS_ATTEMPT"]);
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let state = State {
+        failed_attempts: 2,
+        ..state(ObjectId::empty_tree(Kind::Sha1))
+    };
+    workload.perform(workdir.into_path(), state).unwrap();
+    assert_eq!(
+        events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| matches!(e, WorkloadEvent::ActionOutput(..))),
+        Some(&WorkloadEvent::ActionOutput(
+            "ze-task|ze-action".to_string(),
+            SourceType::StdOut,
+            b"3\n".to_vec(),
+        ))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn workload_skips_given_up_sha() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let next_sha = commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let next_sha = ObjectId::from_hex(next_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let config = config(&upstream, "/usr/bin/false", &[]);
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let prev_sha = ObjectId::empty_tree(Kind::Sha1);
+    let state = State {
+        given_up_sha: Some(next_sha),
+        ..state(prev_sha)
+    };
+    let res = workload.perform(workdir.into_path(), state).unwrap();
+    assert_eq!(res, RunOutcome::Skipped);
+    assert!(events.lock().unwrap().is_empty());
+}
+
+#[test]
+fn watch_failing_fetch() {
+    let upstream = tempfile::tempdir().unwrap();
+    let repodir = tempfile::tempdir().unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let config = config(&upstream, "/bin/ls", &[]);
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let res = workload.perform(workdir.into_path(), State::default());
+    assert!(res.is_err());
+    assert!(!res.unwrap_err().is_fatal());
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert!(matches!(events[0], WorkloadEvent::FetchFailed(..)));
+}
+
+#[cfg(unix)]
+#[test]
+fn defer_changes_outside_deploy_window() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let next_sha = commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let next_sha = ObjectId::from_hex(next_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let mut config = config(&upstream, "/bin/ls", &[]);
+    let other_day = Utc::now().weekday().succ().succ();
+    config.deploy_windows = vec![serde_yaml::from_str(&format!(
+        "{{days: [{}], start: '00:00', end: '23:59'}}",
+        other_day
+    ))
+    .unwrap()];
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let prev_sha = ObjectId::empty_tree(Kind::Sha1);
+    let res = workload
+        .perform(workdir.into_path(), state(prev_sha))
+        .unwrap();
+    assert_eq!(res, RunOutcome::Skipped);
+    assert_eq!(
+        events.lock().unwrap()[..],
+        vec![WorkloadEvent::Deferred("ze-task".to_string(), next_sha)]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn await_dependencies_on_other_sha() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let next_sha = commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let next_sha = ObjectId::from_hex(next_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let config = config(&upstream, "/bin/ls", &[]);
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    workload.require_sha(Some(ObjectId::empty_blob(Kind::Sha1)));
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let prev_sha = ObjectId::empty_tree(Kind::Sha1);
+    let res = workload
+        .perform(workdir.into_path(), state(prev_sha))
+        .unwrap();
+    assert_eq!(res, RunOutcome::Skipped);
+    assert_eq!(
+        events.lock().unwrap()[..],
+        vec![WorkloadEvent::AwaitingDependencies(
+            "ze-task".to_string(),
+            next_sha
+        )]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn roll_back_to_last_successful_sha() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let good_sha = commit_file(&upstream, "revision 1");
+    let bad_sha = commit_file(&upstream, "revision 2");
+    let repodir = tempfile::tempdir().unwrap();
+    let good_sha = ObjectId::from_hex(good_sha.as_bytes()).unwrap();
+    let bad_sha = ObjectId::from_hex(bad_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let mut config = config(
+        &upstream,
+        "/bin/sh",
+        &[
+            "-c",
+            "grep -q 'revision 1' ze-file && test \"$KITOPS_ROLLBACK\" = 1",
+        ],
+    );
+    config.failure_policy = FailurePolicy::Rollback;
+    // Hooks run before the rollback, in the failed run's checkout
+    config.on_failure = serde_yaml::from_str(&format!(
+        r#"[{{name: on-failure, entrypoint: /bin/sh, args: [-c, "grep -q 'revision 2' ze-file && test $KITOPS_SHA = {}"]}}]"#,
+        bad_sha
+    ))
+    .unwrap();
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let res = workload.perform(workdir.into_path(), state(good_sha));
+    assert!(matches!(
+        res,
+        Err(GitOpsError::RolledBack(sha, ref cause))
+            if sha == bad_sha && matches!(**cause, GitOpsError::ActionFailed(..))
+    ));
+    assert_eq!(
+        non_action_events(events)[1..],
+        vec![
+            WorkloadEvent::Failure(
+                "ze-task".to_string(),
+                "ze-task|ze-action".to_string(),
+                bad_sha
+            ),
+            WorkloadEvent::RollingBack("ze-task".to_string(), bad_sha, good_sha),
+            WorkloadEvent::RolledBack("ze-task".to_string(), bad_sha, good_sha, None),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn report_failed_rollback() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let good_sha = commit_file(&upstream, "revision 1");
+    let bad_sha = commit_file(&upstream, "revision 2");
+    let repodir = tempfile::tempdir().unwrap();
+    let good_sha = ObjectId::from_hex(good_sha.as_bytes()).unwrap();
+    let bad_sha = ObjectId::from_hex(bad_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let mut config = config(&upstream, "/usr/bin/false", &[]);
+    config.failure_policy = FailurePolicy::Rollback;
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let res = workload.perform(workdir.into_path(), state(good_sha));
+    // The failed commit stays eligible for retries
+    assert!(matches!(res, Err(GitOpsError::ActionFailed(..))));
+    assert_eq!(
+        non_action_events(events)[2..],
+        vec![
+            WorkloadEvent::RollingBack("ze-task".to_string(), bad_sha, good_sha),
+            WorkloadEvent::RolledBack(
+                "ze-task".to_string(),
+                bad_sha,
+                good_sha,
+                Some("action ze-task|ze-action failed".to_string())
+            ),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn run_hooks_and_clean_up_after_failure() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let workdir = tempfile::tempdir().unwrap().into_path();
+    let mut config = config(&upstream, "/usr/bin/false", &[]);
+    config.on_failure = serde_yaml::from_str(
+        r#"[{name: on-failure, entrypoint: /bin/sh, args: [-c, "echo $KITOPS_RESULT $KITOPS_FAILED_ACTION"]}]"#,
+    )
+    .unwrap();
+    config.always = serde_yaml::from_str(
+        r#"[{name: always, entrypoint: /bin/sh, args: [-c, "echo $KITOPS_RESULT; exit 1"]}]"#,
+    )
+    .unwrap();
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let prev_sha = ObjectId::empty_tree(Kind::Sha1);
+    let res = workload.perform(workdir.clone(), state(prev_sha));
+    assert!(matches!(res, Err(GitOpsError::ActionFailed(..))));
+    assert!(!workdir.exists());
+    let output = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|e| match e {
+            WorkloadEvent::ActionOutput(name, SourceType::StdOut, data) => {
+                Some((name.clone(), String::from_utf8_lossy(data).into_owned()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        output,
+        vec![
+            (
+                "ze-task|on-failure".to_string(),
+                "failure ze-task|ze-action\n".to_string()
+            ),
+            ("ze-task|always".to_string(), "failure\n".to_string()),
+        ]
+    );
+    assert!(
+        non_action_events(events).contains(&WorkloadEvent::HookFailed(
+            "ze-task".to_string(),
+            "action ze-task|always failed".to_string()
+        ))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn per_action_controls() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let prev_sha = commit_file(&upstream, "revision 1");
+    commit_file(&upstream, "revision 2");
+    let repodir = tempfile::tempdir().unwrap();
+    let prev_sha = ObjectId::from_hex(prev_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let mut config = config(&upstream, "/bin/ls", &[]);
+    config.actions = serde_yaml::from_str(
+        r#"
+- name: flaky
+  entrypoint: /bin/sh
+  args: [-c, "test -f marker || { touch marker; exit 1; }"]
+  retries: 1
+- name: slow
+  entrypoint: /bin/sleep
+  args: ["5"]
+  timeout: 100ms
+  continue_on_error: true
+- name: other-paths
+  entrypoint: /bin/ls
+  when:
+    paths: [other]
+- name: ze-file-changed
+  entrypoint: /bin/ls
+  when:
+    branches: [main]
+    paths: [ze-file]
+"#,
+    )
+    .unwrap();
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    workload
+        .perform(workdir.into_path(), state(prev_sha))
+        .unwrap();
+    let results = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|e| match e {
+            WorkloadEvent::ActionFinished(name, result, attempts) => {
+                Some((name.clone(), *result, *attempts))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        vec![
+            ("ze-task|flaky".to_string(), ActionResult::Success, 2),
+            ("ze-task|slow".to_string(), ActionResult::Failure, 1),
+            ("ze-task|other-paths".to_string(), ActionResult::Skipped, 0),
+            (
+                "ze-task|ze-file-changed".to_string(),
+                ActionResult::Success,
+                1
+            ),
+        ]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn run_independent_actions_in_parallel() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let markers = tempfile::tempdir().unwrap();
+    let mut config = config(&upstream, "/bin/ls", &[]);
+    config.parallelism = 3;
+    // Each region waits for the others to start, so they only all report
+    // success when they actually overlap.
+    let region = |name: &str| {
+        format!(
+            r#"
+- name: {name}
+  entrypoint: /bin/sh
+  args:
+    - -c
+    - |
+      touch {dir}/{name}
+      for i in $(seq 100); do
+        [ $(ls {dir} | wc -l) -eq 3 ] && echo {name} && exit 0
+        sleep 0.1
+      done
+      echo {name} ran alone
+"#,
+            name = name,
+            dir = markers.path().display()
+        )
+    };
+    config.actions = serde_yaml::from_str(&format!(
+        r#"{}{}{}
+- name: verify
+  entrypoint: /bin/sh
+  args: [-c, "echo verify"]
+  needs: [region-1, region-2, region-3]
+"#,
+        region("region-1"),
+        region("region-2"),
+        region("region-3")
+    ))
+    .unwrap();
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let prev_sha = ObjectId::empty_tree(Kind::Sha1);
+    workload
+        .perform(workdir.into_path(), state(prev_sha))
+        .unwrap();
+    let output = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|e| match e {
+            WorkloadEvent::ActionOutput(name, SourceType::StdOut, data) => {
+                Some((name.clone(), String::from_utf8_lossy(data).into_owned()))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(output.len(), 4);
+    for (name, data) in &output {
+        assert_eq!(name, &format!("ze-task|{}", data.trim()));
+    }
+    assert_eq!(output[3].0, "ze-task|verify");
+}
+
+#[cfg(unix)]
+#[test]
+fn refuse_unsigned_commit() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let next_sha = commit_file(&upstream, "revision 1");
+    let repodir = tempfile::tempdir().unwrap();
+    let next_sha = ObjectId::from_hex(next_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let mut config = config(&upstream, "/bin/ls", &[]);
+    config.verify = Some(VerifyConfig {
+        allowed_signers: Some("/dev/null".into()),
+        ..Default::default()
+    });
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let res = workload.perform(workdir.into_path(), state(ObjectId::null(Kind::Sha1)));
+    assert!(matches!(res, Err(GitOpsError::UnverifiedCommit(sha, _)) if sha == next_sha));
+    assert_eq!(
+        events.lock().unwrap()[..],
+        vec![WorkloadEvent::Unverified(
+            "ze-task".to_string(),
+            next_sha,
+            "not signed".to_owned()
+        )]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn refuse_non_fast_forward() {
+    let sh = shell();
+    let upstream = empty_repo(&sh);
+    let sha1 = commit_file(&upstream, "revision 1");
+    let deployed_sha = commit_file(&upstream, "revision 2");
+    let repodir = clone_repo(&sh, &upstream);
+    reset_branch(&sh, &upstream, &sha1);
+    let rewritten_sha = commit_file(&upstream, "revision 2 rewritten");
+    let deployed_sha = ObjectId::from_hex(deployed_sha.as_bytes()).unwrap();
+    let rewritten_sha = ObjectId::from_hex(rewritten_sha.as_bytes()).unwrap();
+    let workdir = tempfile::tempdir().unwrap();
+    let mut config = config(&upstream, "/bin/ls", &[]);
+    config.git.non_fast_forward = NonFastForwardPolicy::Refuse;
+    let provider = DefaultUrlProvider::new(config.git.url.clone());
+    let mut workload = GitWorkload::new(config, provider, &repodir.path());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    workload.watch(move |event| {
+        events2.lock().unwrap().push(event);
+        Ok(())
+    });
+    let res = workload
+        .perform(workdir.into_path(), state(deployed_sha))
+        .unwrap();
+    assert_eq!(res, RunOutcome::Skipped);
+    assert_eq!(
+        events.lock().unwrap()[..],
+        vec![WorkloadEvent::NonFastForward(
+            "ze-task".to_string(),
+            deployed_sha,
+            rewritten_sha,
+            true
+        )]
+    );
+}